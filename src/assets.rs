@@ -0,0 +1,367 @@
+use std::collections::{HashMap, HashSet};
+
+use ethers::abi::{decode, Address, Hash, ParamType, Token, Uint};
+use ethers::core::types::{Log, I256};
+use ethers::utils::keccak256;
+use foundry_evm::trace::CallTraceArena;
+use foundry_evm::CallKind;
+use serde::{Deserialize, Serialize};
+
+/// ERC-20 transfers have the amount in the data, ERC-721 transfers index the token id as a
+/// third topic
+const TRANSFER: &str = "Transfer(address,address,uint256)";
+/// ERC-20 approvals have the allowance in the data, ERC-721 approvals index the approved token id
+/// as a third topic
+const APPROVAL: &str = "Approval(address,address,uint256)";
+/// Emitted in the same shape by ERC-721 and ERC-1155, so the standard is told apart by ERC-165
+const APPROVAL_FOR_ALL: &str = "ApprovalForAll(address,address,bool)";
+const TRANSFER_SINGLE: &str = "TransferSingle(address,address,address,uint256,uint256)";
+const TRANSFER_BATCH: &str = "TransferBatch(address,address,address,uint256[],uint256[])";
+
+/// The ERC-165 interface id of ERC-1155
+pub const ERC1155_INTERFACE_ID: [u8; 4] = [0xd9, 0xb6, 0x7a, 0x26];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum AssetStandard {
+    Native,
+    Erc20,
+    Erc721,
+    Erc1155,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Asset {
+    standard: AssetStandard,
+    token: Option<Address>,
+    token_id: Option<Uint>,
+}
+
+impl Asset {
+    fn native() -> Self {
+        Asset {
+            standard: AssetStandard::Native,
+            token: None,
+            token_id: None,
+        }
+    }
+}
+
+/// The net change of an asset held by an address. `token` is not set for ETH, and `tokenId`
+/// is only set for ERC-721 and ERC-1155 tokens.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceChange {
+    pub address: Address,
+    pub standard: AssetStandard,
+    pub token: Option<Address>,
+    pub token_id: Option<Uint>,
+    #[serde(with = "signed_decimal")]
+    pub delta: I256,
+}
+
+/// An approval emitted during the simulation. `amount` is set for ERC-20 approvals, `tokenId`
+/// for ERC-721 approvals and `approved` for operator approvals of a whole collection.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApprovalChange {
+    pub standard: AssetStandard,
+    pub token: Address,
+    pub owner: Address,
+    pub spender: Address,
+    pub amount: Option<Uint>,
+    pub token_id: Option<Uint>,
+    pub approved: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetChanges {
+    pub balances: Vec<BalanceChange>,
+    pub approvals: Vec<ApprovalChange>,
+}
+
+#[derive(Default)]
+struct AssetChangesBuilder {
+    deltas: HashMap<(Address, Asset), I256>,
+    order: Vec<(Address, Asset)>,
+    approvals: Vec<ApprovalChange>,
+}
+
+impl AssetChangesBuilder {
+    fn transfer(&mut self, from: Address, to: Address, asset: Asset, amount: Uint) {
+        if amount.is_zero() || from == to {
+            return;
+        }
+        // Amounts above `I256::MAX` can't be held by any real token, so they are saturated
+        let amount = I256::try_from(amount).unwrap_or(I256::MAX);
+        self.add(from, asset, -amount);
+        self.add(to, asset, amount);
+    }
+
+    fn add(&mut self, address: Address, asset: Asset, amount: I256) {
+        let key = (address, asset);
+        if !self.deltas.contains_key(&key) {
+            self.order.push(key);
+        }
+        let delta = self.deltas.entry(key).or_default();
+        *delta = delta.saturating_add(amount);
+    }
+
+    fn build(self) -> AssetChanges {
+        let balances = self
+            .order
+            .into_iter()
+            .filter_map(|(address, asset)| {
+                let delta = self.deltas[&(address, asset)];
+                (!delta.is_zero()).then_some(BalanceChange {
+                    address,
+                    standard: asset.standard,
+                    token: asset.token,
+                    token_id: asset.token_id,
+                    delta,
+                })
+            })
+            .collect();
+
+        AssetChanges {
+            balances,
+            approvals: self.approvals,
+        }
+    }
+}
+
+/// The contracts which emitted `ApprovalForAll`, which have to be checked for ERC-1155 support
+pub fn approval_for_all_tokens(logs: &[Log]) -> HashSet<Address> {
+    let topic = Hash::from(keccak256(APPROVAL_FOR_ALL));
+    logs.iter()
+        .filter(|log| log.topics.first() == Some(&topic))
+        .map(|log| log.address)
+        .collect()
+}
+
+/// Extracts the net balance changes and approvals of a simulation from its logs, and the ETH
+/// moved by the calls in its trace. `erc1155_tokens` are the contracts known to be ERC-1155
+/// tokens, the operator approvals of other contracts are taken to be ERC-721 approvals.
+pub fn asset_changes(
+    logs: &[Log],
+    trace: &CallTraceArena,
+    erc1155_tokens: &HashSet<Address>,
+) -> AssetChanges {
+    let mut builder = AssetChangesBuilder::default();
+
+    for node in &trace.arena {
+        let call = &node.trace;
+        if call.value.is_zero()
+            || matches!(
+                call.kind,
+                CallKind::StaticCall | CallKind::DelegateCall | CallKind::CallCode
+            )
+        {
+            continue;
+        }
+
+        // A call's value transfer is undone if the call or any of its callers reverted
+        let mut reverted = !call.success;
+        let mut parent = node.parent;
+        while let Some(idx) = parent {
+            reverted |= !trace.arena[idx].trace.success;
+            parent = trace.arena[idx].parent;
+        }
+        if !reverted {
+            builder.transfer(call.caller, call.address, Asset::native(), call.value);
+        }
+    }
+
+    for log in logs {
+        decode_log(&mut builder, log, erc1155_tokens);
+    }
+
+    builder.build()
+}
+
+fn decode_log(builder: &mut AssetChangesBuilder, log: &Log, erc1155_tokens: &HashSet<Address>) {
+    let Some(topic) = log.topics.first() else {
+        return;
+    };
+    let Some(event) = [
+        TRANSFER,
+        APPROVAL,
+        APPROVAL_FOR_ALL,
+        TRANSFER_SINGLE,
+        TRANSFER_BATCH,
+    ]
+    .into_iter()
+    .find(|event| Hash::from(keccak256(event)) == *topic) else {
+        return;
+    };
+    let topic_address = |idx: usize| log.topics.get(idx).map(|topic| Address::from(*topic));
+    let topic_uint = |idx: usize| {
+        log.topics
+            .get(idx)
+            .map(|topic| Uint::from_big_endian(topic.as_bytes()))
+    };
+
+    match (event, log.topics.len()) {
+        // ERC-20 transfers have the amount in the data
+        (TRANSFER, 3) => {
+            let (Some(from), Some(to), Some(amount)) =
+                (topic_address(1), topic_address(2), data_uint(&log.data))
+            else {
+                return;
+            };
+            let asset = Asset {
+                standard: AssetStandard::Erc20,
+                token: Some(log.address),
+                token_id: None,
+            };
+            builder.transfer(from, to, asset, amount);
+        }
+        // ERC-721 transfers have the token id as a topic
+        (TRANSFER, 4) => {
+            let (Some(from), Some(to), Some(token_id)) =
+                (topic_address(1), topic_address(2), topic_uint(3))
+            else {
+                return;
+            };
+            let asset = Asset {
+                standard: AssetStandard::Erc721,
+                token: Some(log.address),
+                token_id: Some(token_id),
+            };
+            builder.transfer(from, to, asset, Uint::one());
+        }
+        (APPROVAL, 3) => {
+            let (Some(owner), Some(spender), Some(amount)) =
+                (topic_address(1), topic_address(2), data_uint(&log.data))
+            else {
+                return;
+            };
+            builder.approvals.push(ApprovalChange {
+                standard: AssetStandard::Erc20,
+                token: log.address,
+                owner,
+                spender,
+                amount: Some(amount),
+                token_id: None,
+                approved: None,
+            });
+        }
+        (APPROVAL, 4) => {
+            let (Some(owner), Some(spender), Some(token_id)) =
+                (topic_address(1), topic_address(2), topic_uint(3))
+            else {
+                return;
+            };
+            builder.approvals.push(ApprovalChange {
+                standard: AssetStandard::Erc721,
+                token: log.address,
+                owner,
+                spender,
+                amount: None,
+                token_id: Some(token_id),
+                approved: Some(true),
+            });
+        }
+        (APPROVAL_FOR_ALL, 3) => {
+            let (Some(owner), Some(spender), Some(approved)) =
+                (topic_address(1), topic_address(2), data_uint(&log.data))
+            else {
+                return;
+            };
+            let standard = if erc1155_tokens.contains(&log.address) {
+                AssetStandard::Erc1155
+            } else {
+                AssetStandard::Erc721
+            };
+            builder.approvals.push(ApprovalChange {
+                standard,
+                token: log.address,
+                owner,
+                spender,
+                amount: None,
+                token_id: None,
+                approved: Some(!approved.is_zero()),
+            });
+        }
+        (TRANSFER_SINGLE, 4) => {
+            let (Some(from), Some(to)) = (topic_address(2), topic_address(3)) else {
+                return;
+            };
+            let Ok(tokens) = decode(&[ParamType::Uint(256), ParamType::Uint(256)], &log.data)
+            else {
+                return;
+            };
+            if let [Token::Uint(token_id), Token::Uint(amount)] = tokens.as_slice() {
+                let asset = Asset {
+                    standard: AssetStandard::Erc1155,
+                    token: Some(log.address),
+                    token_id: Some(*token_id),
+                };
+                builder.transfer(from, to, asset, *amount);
+            }
+        }
+        (TRANSFER_BATCH, 4) => {
+            let (Some(from), Some(to)) = (topic_address(2), topic_address(3)) else {
+                return;
+            };
+            let uint_array = ParamType::Array(Box::new(ParamType::Uint(256)));
+            let Ok(tokens) = decode(&[uint_array.clone(), uint_array], &log.data) else {
+                return;
+            };
+            if let [Token::Array(token_ids), Token::Array(amounts)] = tokens.as_slice() {
+                for (token_id, amount) in token_ids.iter().zip(amounts) {
+                    if let (Token::Uint(token_id), Token::Uint(amount)) = (token_id, amount) {
+                        let asset = Asset {
+                            standard: AssetStandard::Erc1155,
+                            token: Some(log.address),
+                            token_id: Some(*token_id),
+                        };
+                        builder.transfer(from, to, asset, *amount);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn data_uint(data: &[u8]) -> Option<Uint> {
+    (data.len() == 32).then(|| Uint::from_big_endian(data))
+}
+
+/// (De)serializes signed amounts as decimal strings, e.g. `"-1000"`
+mod signed_decimal {
+    use ethers::core::types::I256;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &I256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<I256, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        I256::from_dec_str(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_saturates_amounts_above_i256_max() {
+        let from = Address::repeat_byte(1);
+        let to = Address::repeat_byte(2);
+        let mut builder = AssetChangesBuilder::default();
+        builder.transfer(from, to, Asset::native(), Uint::MAX);
+        builder.transfer(from, to, Asset::native(), Uint::MAX);
+
+        let changes = builder.build();
+        assert_eq!(changes.balances.len(), 2);
+        assert_eq!(changes.balances[0].address, from);
+        assert_eq!(changes.balances[0].delta, I256::MIN);
+        assert_eq!(changes.balances[1].address, to);
+        assert_eq!(changes.balances[1].delta, I256::MAX);
+    }
+}
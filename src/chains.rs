@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ethers::abi::{Address, Hash, Uint};
+use eyre::{bail, eyre, Context};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::simulation::PermissiveUint;
+use crate::state_dump::{DumpBlock, StateDump};
+
+/// Used for requests without a gas limit, on chains which don't configure their own
+pub const DEFAULT_GAS_LIMIT: u64 = 30_000_000;
+
+/// How to reach a chain, as configured in the chains file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChainConfig {
+    /// RPC endpoints in order of preference
+    #[serde(default)]
+    pub rpc_urls: Vec<String>,
+    /// Sent with every request to the RPC endpoints
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// The API of an Etherscan-style explorer, used to identify contracts in traces
+    pub explorer_url: Option<String>,
+    pub explorer_key: Option<String>,
+    #[serde(default = "default_gas_limit")]
+    pub default_gas_limit: u64,
+    /// Simulate the chain from a state file or an empty state instead of forking it
+    pub local: Option<LocalChain>,
+}
+
+/// A chain which isn't forked from an RPC, but starts out from the state in its state file. The
+/// block environment set here takes precedence over the one in the state file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct LocalChain {
+    /// An anvil state dump or a geth genesis file, relative to the chains file. Without one the
+    /// chain starts out empty.
+    pub state: Option<PathBuf>,
+    pub block_number: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub block_gas_limit: Option<u64>,
+    pub base_fee: Option<u64>,
+    pub coinbase: Option<Address>,
+    pub prevrandao: Option<Hash>,
+    /// Read from `state` when the chains file is loaded
+    #[serde(skip)]
+    pub dump: Arc<StateDump>,
+}
+
+impl LocalChain {
+    /// The block environment set in the config
+    pub fn block(&self) -> DumpBlock {
+        let uint = |value: Option<u64>| value.map(|value| PermissiveUint(Uint::from(value)));
+        DumpBlock {
+            number: uint(self.block_number),
+            coinbase: self.coinbase,
+            timestamp: uint(self.timestamp),
+            difficulty: None,
+            prevrandao: self.prevrandao,
+            basefee: uint(self.base_fee),
+            gas_limit: uint(self.block_gas_limit),
+        }
+    }
+
+    fn load(&mut self, base: &Path) -> eyre::Result<()> {
+        if let Some(state) = &self.state {
+            self.dump = Arc::new(StateDump::load(&base.join(state))?);
+        }
+        Ok(())
+    }
+}
+
+impl ChainConfig {
+    pub fn new(rpc_url: String) -> Self {
+        ChainConfig {
+            rpc_urls: vec![rpc_url],
+            headers: BTreeMap::new(),
+            explorer_url: None,
+            explorer_key: None,
+            default_gas_limit: DEFAULT_GAS_LIMIT,
+            local: None,
+        }
+    }
+
+    pub fn local(local: LocalChain) -> Self {
+        ChainConfig {
+            rpc_urls: vec![],
+            local: Some(local),
+            ..ChainConfig::new(String::new())
+        }
+    }
+
+    /// The preferred RPC endpoint. Every chain which isn't local has at least one, which is
+    /// checked on load.
+    pub fn rpc_url(&self) -> &str {
+        &self.rpc_urls[0]
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        self.headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::try_from(name.as_str()).ok()?,
+                    HeaderValue::try_from(value.as_str()).ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> eyre::Result<()> {
+        match (&self.local, self.rpc_urls.is_empty()) {
+            (None, true) => bail!("at least one RPC URL is required"),
+            (Some(_), false) => bail!("a local chain can't have RPC URLs"),
+            _ => {}
+        }
+        for url in &self.rpc_urls {
+            Url::parse(url).wrap_err_with(|| format!("invalid RPC URL {url}"))?;
+        }
+        for (name, value) in &self.headers {
+            HeaderName::try_from(name.as_str())
+                .wrap_err_with(|| format!("invalid header name {name}"))?;
+            HeaderValue::try_from(value.as_str())
+                .wrap_err_with(|| format!("invalid value for header {name}"))?;
+        }
+        if let Some(url) = &self.explorer_url {
+            Url::parse(url).wrap_err_with(|| format!("invalid explorer URL {url}"))?;
+        }
+        if self.default_gas_limit == 0 {
+            bail!("the default gas limit must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+fn default_gas_limit() -> u64 {
+    DEFAULT_GAS_LIMIT
+}
+
+#[derive(Debug, Deserialize)]
+struct ChainsFile {
+    chains: HashMap<String, ChainConfig>,
+}
+
+/// Loads the chains from a TOML file, or a JSON file if its extension is `.json`, along with the
+/// state files of local chains
+pub fn load_chains(path: &Path) -> eyre::Result<HashMap<u64, ChainConfig>> {
+    let contents = std::fs::read_to_string(path)?;
+    let is_json = path
+        .extension()
+        .map_or(false, |extension| extension == "json");
+    let mut chains = parse_chains(&contents, is_json)?;
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    for (chain_id, chain) in chains.iter_mut() {
+        if let Some(local) = &mut chain.local {
+            local
+                .load(base)
+                .wrap_err_with(|| format!("invalid config for chain {chain_id}"))?;
+        }
+    }
+
+    Ok(chains)
+}
+
+fn parse_chains(contents: &str, is_json: bool) -> eyre::Result<HashMap<u64, ChainConfig>> {
+    let file: ChainsFile = if is_json {
+        serde_json::from_str(contents)?
+    } else {
+        toml::from_str(contents)?
+    };
+
+    file.chains
+        .into_iter()
+        .map(|(chain_id, chain)| {
+            let chain_id = chain_id
+                .parse::<u64>()
+                .map_err(|_| eyre!("invalid chain id {chain_id}"))?;
+            chain
+                .validate()
+                .wrap_err_with(|| format!("invalid config for chain {chain_id}"))?;
+            Ok((chain_id, chain))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chains_toml() {
+        let chains = parse_chains(
+            r#"
+            [chains.1]
+            rpc_urls = ["https://mainnet.example.com", "https://fallback.example.com"]
+            headers = { Authorization = "Bearer secret" }
+            explorer_url = "https://api.etherscan.io/api"
+            explorer_key = "key"
+            default_gas_limit = 50000000
+
+            [chains.10]
+            rpc_urls = ["https://optimism.example.com"]
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let mainnet = &chains[&1];
+        assert_eq!(mainnet.rpc_url(), "https://mainnet.example.com");
+        assert_eq!(mainnet.rpc_urls.len(), 2);
+        assert_eq!(mainnet.header_map()["authorization"], "Bearer secret");
+        assert_eq!(mainnet.explorer_key, Some("key".to_string()));
+        assert_eq!(mainnet.default_gas_limit, 50000000);
+
+        assert_eq!(
+            chains[&10],
+            ChainConfig::new("https://optimism.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_chains_json() {
+        let chains = parse_chains(
+            r#"{ "chains": { "137": { "rpc_urls": ["https://polygon.example.com"] } } }"#,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(chains[&137].rpc_url(), "https://polygon.example.com");
+        assert_eq!(chains[&137].default_gas_limit, DEFAULT_GAS_LIMIT);
+    }
+
+    #[test]
+    fn test_parse_chains_local() {
+        let chains = parse_chains(
+            r#"
+            [chains.31337.local]
+            state = "genesis.json"
+            block_number = 10
+            base_fee = 7
+
+            [chains.1337]
+            local = {}
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let local = chains[&31337].local.as_ref().unwrap();
+        assert!(chains[&31337].rpc_urls.is_empty());
+        assert_eq!(local.state, Some(PathBuf::from("genesis.json")));
+        assert_eq!(local.block_number, Some(10));
+        assert_eq!(local.base_fee, Some(7));
+        // State files are only read by `load_chains`
+        assert_eq!(*local.dump, StateDump::default());
+
+        assert_eq!(chains[&1337], ChainConfig::local(LocalChain::default()));
+    }
+
+    #[test]
+    fn test_parse_chains_invalid() {
+        let invalid = [
+            "[chains.mainnet]\nrpc_urls = [\"https://mainnet.example.com\"]",
+            "[chains.1]\nrpc_urls = []",
+            "[chains.1]\nrpc_urls = [\"not a url\"]",
+            "[chains.1]\nrpc_urls = [\"https://mainnet.example.com\"]\nheaders = { \"bad header\" = \"a\" }",
+            "[chains.1]\nrpc_urls = [\"https://mainnet.example.com\"]\ndefault_gas_limit = 0",
+            "[chains.1]\nrpc_urls = [\"https://mainnet.example.com\"]\nlocal = {}",
+            "[chains.1]\nlocal = { coinbase = \"not an address\" }",
+        ];
+
+        for contents in invalid {
+            assert!(parse_chains(contents, false).is_err(), "{contents}");
+        }
+    }
+}
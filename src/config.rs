@@ -1,13 +1,28 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use dotenvy::dotenv;
 
+use crate::chains::{load_chains, ChainConfig};
+
+/// Read when `CHAINS_CONFIG` isn't set
+const DEFAULT_CHAINS_CONFIG: &str = "chains.toml";
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
     pub uds_path: Option<String>,
-    pub fork_url: Option<String>,
+    pub chains: HashMap<u64, ChainConfig>,
     pub etherscan_key: Option<String>,
     pub api_key: Option<String>,
     pub max_request_size: u64,
+    pub fork_pool_size: usize,
+    pub session_ttl: Option<Duration>,
+    pub max_sessions: usize,
+    pub fork_cache_dir: Option<PathBuf>,
+    /// In bytes
+    pub fork_cache_size: u64,
 }
 
 pub fn config() -> Config {
@@ -22,7 +37,27 @@ fn load_config() -> Config {
         .parse::<u16>()
         .expect("PORT must be a valid u16.");
     let uds_path = std::env::var("UDS_PATH").ok().filter(|k| !k.is_empty());
-    let fork_url = std::env::var("FORK_URL").ok().filter(|k| !k.is_empty());
+    if std::env::var("FORK_URL").map_or(false, |url| !url.is_empty()) {
+        log::warn!(
+            target: "ts::config",
+            "FORK_URL is ignored, list the RPC endpoints of each chain in CHAINS_CONFIG instead"
+        );
+    }
+    let chains_config = std::env::var("CHAINS_CONFIG")
+        .ok()
+        .filter(|k| !k.is_empty());
+    let chains = match chains_config {
+        Some(path) => load_chains(Path::new(&path))
+            .unwrap_or_else(|err| panic!("CHAINS_CONFIG must be a valid chains file: {err:#}")),
+        None if Path::new(DEFAULT_CHAINS_CONFIG).exists() => {
+            load_chains(Path::new(DEFAULT_CHAINS_CONFIG))
+                .unwrap_or_else(|err| panic!("{DEFAULT_CHAINS_CONFIG} is invalid: {err:#}"))
+        }
+        None => {
+            log::warn!(target: "ts::config", "No chains configured, set CHAINS_CONFIG");
+            HashMap::new()
+        }
+    };
     let etherscan_key = std::env::var("ETHERSCAN_KEY")
         .ok()
         .filter(|k| !k.is_empty());
@@ -32,19 +67,49 @@ fn load_config() -> Config {
         .parse::<u64>()
         .expect("MAX_REQUEST_SIZE must be a valid u64")
         * 1024;
+    let fork_pool_size = std::env::var("FORK_POOL_SIZE")
+        .unwrap_or("16".to_string())
+        .parse::<usize>()
+        .expect("FORK_POOL_SIZE must be a valid usize");
+    let session_ttl = std::env::var("SESSION_TTL")
+        .unwrap_or("3600".to_string())
+        .parse::<u64>()
+        .expect("SESSION_TTL must be a valid u64");
+    let session_ttl = (session_ttl > 0).then(|| Duration::from_secs(session_ttl));
+    let max_sessions = std::env::var("MAX_SESSIONS")
+        .unwrap_or("100".to_string())
+        .parse::<usize>()
+        .expect("MAX_SESSIONS must be a valid usize");
+    let fork_cache_dir = std::env::var("FORK_CACHE_DIR")
+        .ok()
+        .filter(|k| !k.is_empty())
+        .map(PathBuf::from);
+    let fork_cache_size = std::env::var("FORK_CACHE_SIZE")
+        .unwrap_or("1024".to_string())
+        .parse::<u64>()
+        .expect("FORK_CACHE_SIZE must be a valid u64")
+        * 1024
+        * 1024;
 
     Config {
-        fork_url,
+        chains,
         port,
         uds_path,
         etherscan_key,
         api_key,
         max_request_size,
+        fork_pool_size,
+        session_ttl,
+        max_sessions,
+        fork_cache_dir,
+        fork_cache_size,
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     #[test]
     #[should_panic(expected = "PORT must be a valid u16.")]
     fn test_config_port_number() {
@@ -54,20 +119,26 @@ mod tests {
     }
 
     #[test]
-    fn test_config_fork_url() {
-        temp_env::with_vars([("FORK_URL", Some("a"))], || {
-            let config = super::load_config();
-            assert_eq!(config.fork_url, Some("a".to_string()));
-        });
+    fn test_config_chains() {
+        let path = std::env::temp_dir().join("temper-test-chains.json");
+        std::fs::write(
+            &path,
+            r#"{ "chains": { "1": { "rpc_urls": ["https://mainnet.example.com"] } } }"#,
+        )
+        .unwrap();
 
-        temp_env::with_vars([("FORK_URL", Some(""))], || {
+        temp_env::with_vars([("CHAINS_CONFIG", Some(path.to_str().unwrap()))], || {
             let config = super::load_config();
-            assert_eq!(config.fork_url, None);
+            assert_eq!(config.chains.len(), 1);
+            assert_eq!(config.chains[&1].rpc_url(), "https://mainnet.example.com");
         });
+    }
 
-        temp_env::with_vars_unset([("FORK_URL")], || {
-            let config = super::load_config();
-            assert_eq!(config.fork_url, None);
+    #[test]
+    #[should_panic(expected = "CHAINS_CONFIG must be a valid chains file")]
+    fn test_config_chains_missing_file() {
+        temp_env::with_var("CHAINS_CONFIG", Some("/nonexistent/chains.toml"), || {
+            super::load_config();
         });
     }
 
@@ -106,4 +177,72 @@ mod tests {
             assert_eq!(config.api_key, None);
         });
     }
+
+    #[test]
+    fn test_config_fork_pool_size() {
+        temp_env::with_vars([("FORK_POOL_SIZE", Some("4"))], || {
+            let config = super::load_config();
+            assert_eq!(config.fork_pool_size, 4);
+        });
+
+        temp_env::with_vars_unset([("FORK_POOL_SIZE")], || {
+            let config = super::load_config();
+            assert_eq!(config.fork_pool_size, 16);
+        });
+    }
+
+    #[test]
+    fn test_config_session_ttl() {
+        temp_env::with_vars([("SESSION_TTL", Some("60"))], || {
+            let config = super::load_config();
+            assert_eq!(config.session_ttl, Some(Duration::from_secs(60)));
+        });
+
+        temp_env::with_vars([("SESSION_TTL", Some("0"))], || {
+            let config = super::load_config();
+            assert_eq!(config.session_ttl, None);
+        });
+
+        temp_env::with_vars_unset([("SESSION_TTL")], || {
+            let config = super::load_config();
+            assert_eq!(config.session_ttl, Some(Duration::from_secs(3600)));
+        });
+    }
+
+    #[test]
+    fn test_config_max_sessions() {
+        temp_env::with_vars([("MAX_SESSIONS", Some("5"))], || {
+            let config = super::load_config();
+            assert_eq!(config.max_sessions, 5);
+        });
+
+        temp_env::with_vars_unset([("MAX_SESSIONS")], || {
+            let config = super::load_config();
+            assert_eq!(config.max_sessions, 100);
+        });
+    }
+
+    #[test]
+    fn test_config_fork_cache() {
+        temp_env::with_vars(
+            [
+                ("FORK_CACHE_DIR", Some("/var/cache/temper")),
+                ("FORK_CACHE_SIZE", Some("2")),
+            ],
+            || {
+                let config = super::load_config();
+                assert_eq!(
+                    config.fork_cache_dir,
+                    Some(std::path::PathBuf::from("/var/cache/temper"))
+                );
+                assert_eq!(config.fork_cache_size, 2 * 1024 * 1024);
+            },
+        );
+
+        temp_env::with_vars_unset([("FORK_CACHE_DIR"), ("FORK_CACHE_SIZE")], || {
+            let config = super::load_config();
+            assert_eq!(config.fork_cache_dir, None);
+            assert_eq!(config.fork_cache_size, 1024 * 1024 * 1024);
+        });
+    }
 }
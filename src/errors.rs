@@ -4,6 +4,8 @@ use std::{convert::Infallible, error::Error};
 
 use warp::{body::BodyDeserializeError, hyper::StatusCode, reject::Reject, Rejection, Reply};
 
+use crate::fees::FeeError;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ErrorMessage {
     pub code: u16,
@@ -40,11 +42,56 @@ pub struct StateNotFound();
 
 impl Reject for StateNotFound {}
 
+#[derive(Debug)]
+pub struct TooManySessionsError();
+
+impl Reject for TooManySessionsError {}
+
+#[derive(Debug)]
+pub struct SnapshotNotFound();
+
+impl Reject for SnapshotNotFound {}
+
+#[derive(Debug)]
+pub struct InvalidTransactionError();
+
+impl Reject for InvalidTransactionError {}
+
+#[derive(Debug)]
+pub struct TransactionNotFoundError();
+
+impl Reject for TransactionNotFoundError {}
+
+#[derive(Debug)]
+pub struct BlockNotFoundError();
+
+impl Reject for BlockNotFoundError {}
+
+#[derive(Debug)]
+pub struct RpcError(pub Report);
+
+impl Reject for RpcError {}
+
+#[derive(Debug)]
+pub struct ForkCacheDisabledError();
+
+impl Reject for ForkCacheDisabledError {}
+
+#[derive(Debug)]
+pub struct LocalChainError();
+
+impl Reject for LocalChainError {}
+
 #[derive(Debug)]
 pub struct OverrideError;
 
 impl Reject for OverrideError {}
 
+#[derive(Debug)]
+pub struct ExecutionRevertedError();
+
+impl Reject for ExecutionRevertedError {}
+
 #[derive(Debug)]
 pub struct EvmError(pub Report);
 
@@ -60,6 +107,12 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     } else if let Some(_e) = err.find::<StateNotFound>() {
         code = StatusCode::NOT_FOUND;
         message = "STATE_NOT_FOUND".to_string();
+    } else if let Some(_e) = err.find::<TooManySessionsError>() {
+        code = StatusCode::TOO_MANY_REQUESTS;
+        message = "TOO_MANY_STATEFUL_SIMULATIONS".to_string();
+    } else if let Some(_e) = err.find::<SnapshotNotFound>() {
+        code = StatusCode::NOT_FOUND;
+        message = "SNAPSHOT_NOT_FOUND".to_string();
     } else if let Some(NoURLForChainIdError) = err.find() {
         code = StatusCode::BAD_REQUEST;
         message = "CHAIN_ID_NOT_SUPPORTED".to_string();
@@ -75,13 +128,49 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     } else if let Some(_e) = err.find::<InvalidBlockNumbersError>() {
         code = StatusCode::BAD_REQUEST;
         message = "INVALID_BLOCK_NUMBERS".to_string();
+    } else if let Some(_e) = err.find::<InvalidTransactionError>() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_TRANSACTION".to_string();
+    } else if let Some(_e) = err.find::<TransactionNotFoundError>() {
+        code = StatusCode::NOT_FOUND;
+        message = "TRANSACTION_NOT_FOUND".to_string();
+    } else if let Some(_e) = err.find::<BlockNotFoundError>() {
+        code = StatusCode::NOT_FOUND;
+        message = "BLOCK_NOT_FOUND".to_string();
+    } else if let Some(_e) = err.find::<RpcError>() {
+        code = StatusCode::BAD_GATEWAY;
+        message = "RPC_ERROR".to_string();
+    } else if let Some(_e) = err.find::<ForkCacheDisabledError>() {
+        code = StatusCode::NOT_FOUND;
+        message = "FORK_CACHE_DISABLED".to_string();
+    } else if let Some(_e) = err.find::<LocalChainError>() {
+        code = StatusCode::BAD_REQUEST;
+        message = "NOT_SUPPORTED_ON_LOCAL_CHAIN".to_string();
     } else if let Some(_e) = err.find::<OverrideError>() {
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = "OVERRIDE_ERROR".to_string();
+    } else if let Some(_e) = err.find::<ExecutionRevertedError>() {
+        code = StatusCode::BAD_REQUEST;
+        message = "EXECUTION_REVERTED".to_string();
     } else if let Some(_e) = err.find::<EvmError>() {
-        if _e.0.to_string().contains("CallGasCostMoreThanGasLimit") {
+        let error = _e.0.to_string();
+        if let Some(fee_error) = _e.0.downcast_ref::<FeeError>() {
+            code = StatusCode::BAD_REQUEST;
+            message = match fee_error {
+                FeeError::InsufficientFundsForGas => "INSUFFICIENT_FUNDS_FOR_GAS",
+                FeeError::MaxFeePerGasLessThanBaseFee => "MAX_FEE_PER_GAS_LESS_THAN_BASE_FEE",
+                FeeError::PriorityFeeGreaterThanMaxFee => "PRIORITY_FEE_GREATER_THAN_MAX_FEE",
+            }
+            .to_string();
+        } else if error.contains("CallGasCostMoreThanGasLimit") {
             code = StatusCode::BAD_REQUEST;
             message = "OUT_OF_GAS".to_string();
+        } else if error.contains("NonceTooHigh") {
+            code = StatusCode::BAD_REQUEST;
+            message = "NONCE_TOO_HIGH".to_string();
+        } else if error.contains("NonceTooLow") {
+            code = StatusCode::BAD_REQUEST;
+            message = "NONCE_TOO_LOW".to_string();
         } else {
             code = StatusCode::INTERNAL_SERVER_ERROR;
             message = "EVM_ERROR".to_string();
@@ -1,32 +1,91 @@
 use std::collections::HashMap;
 
-use ethers::abi::{Address, Hash, Uint};
+use ethers::abi::{encode, Address, Hash, Token, Uint};
 use ethers::core::types::Log;
 use ethers::types::transaction::eip2930::AccessList;
-use ethers::types::Bytes;
+use ethers::types::{Bytes, Transaction, TransactionReceipt};
+use foundry_config::etherscan::{EtherscanApiKey, EtherscanConfig, EtherscanConfigs};
 use foundry_config::Chain;
-use foundry_evm::executor::{fork::CreateFork, Executor};
-use foundry_evm::executor::{opts::EvmOpts, Backend, ExecutorBuilder};
-use foundry_evm::trace::identifier::{EtherscanIdentifier, SignaturesIdentifier};
+use foundry_evm::executor::inspector::AccessListTracer;
+use foundry_evm::executor::{
+    backend::{Backend, DatabaseExt},
+    Executor, ExecutorBuilder,
+};
+use foundry_evm::trace::identifier::{
+    EtherscanIdentifier, SignaturesIdentifier, SingleSignaturesIdentifier,
+};
 use foundry_evm::trace::node::CallTraceNode;
-use foundry_evm::trace::{CallTraceArena, CallTraceDecoder, CallTraceDecoderBuilder};
-use foundry_evm::utils::{h160_to_b160, u256_to_ru256};
+use foundry_evm::trace::{
+    CallTraceArena, CallTraceDecoder, CallTraceDecoderBuilder, RawOrDecodedCall,
+    RawOrDecodedReturnData,
+};
+use foundry_evm::utils::{
+    b160_to_h160, b256_to_h256, h160_to_b160, h256_to_b256, ru256_to_u256, u256_to_ru256,
+};
 use revm::db::DatabaseRef;
 use revm::interpreter::InstructionResult;
-use revm::primitives::{Account, Bytecode, Env, StorageSlot};
-use revm::DatabaseCommit;
+use revm::primitives::{
+    Account, AccountInfo, Bytecode, CreateScheme, Env, Output, StorageSlot, TransactTo, B160,
+    U256 as rU256,
+};
+use revm::{DatabaseCommit, Inspector, JournaledState};
 
+use crate::chains::ChainConfig;
 use crate::errors::{EvmError, OverrideError};
+use crate::fees::{charged_gas_price, validate_fees};
+use crate::fork::Fork;
+use crate::revert::RevertReason;
 use crate::simulation::CallTrace;
+use crate::state_diff::{state_diff, AccountDiff};
+use crate::state_dump::{changed_accounts, DumpBlock, StateDump};
+use crate::tracer::{
+    call_frame, four_bytes, precompiles, prestate, CallGasTracer, GethTrace, GethTracer, StructLog,
+    StructLogTracer, TraceConfig,
+};
 
 #[derive(Debug, Clone)]
 pub struct CallRawRequest {
     pub from: Address,
-    pub to: Address,
+    pub to: Option<Address>,
     pub value: Option<Uint>,
     pub data: Option<Bytes>,
     pub access_list: Option<AccessList>,
+    /// Checked against the sender's nonce if set
+    pub nonce: Option<u64>,
+    pub gas_price: Option<Uint>,
+    pub max_fee_per_gas: Option<Uint>,
+    pub max_priority_fee_per_gas: Option<Uint>,
+    /// Charge the sender for gas at the block's base fee, failing if it can't pay
+    pub realistic_fees: bool,
+    /// Decodes the functions of the calls in the trace, which `format_trace` does as well
+    pub decode_trace: bool,
     pub format_trace: bool,
+    pub state_diff: bool,
+    /// Logs every step of execution with the opcode tracer if set
+    pub trace_config: Option<TraceConfig>,
+}
+
+/// Signed transactions are executed like on the network, so the nonce has to match and the sender
+/// has to pay for gas
+impl From<Transaction> for CallRawRequest {
+    fn from(transaction: Transaction) -> Self {
+        CallRawRequest {
+            from: transaction.from,
+            to: transaction.to,
+            value: Some(transaction.value),
+            data: Some(transaction.input),
+            access_list: transaction.access_list,
+            nonce: Some(transaction.nonce.low_u64()),
+            gas_price: transaction.gas_price,
+            max_fee_per_gas: transaction.max_fee_per_gas,
+            max_priority_fee_per_gas: transaction.max_priority_fee_per_gas,
+            realistic_fees: true,
+            decode_trace: false,
+            format_trace: false,
+            state_diff: false,
+            trace_config: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -35,10 +94,25 @@ pub struct CallRawResult {
     pub block_number: u64,
     pub success: bool,
     pub trace: Option<CallTraceArena>,
+    pub decoded_trace: Option<CallTraceArena>,
     pub logs: Vec<Log>,
     pub exit_reason: InstructionResult,
     pub return_data: Bytes,
     pub formatted_trace: Option<String>,
+    pub revert_reason: Option<RevertReason>,
+    pub deployed_address: Option<Address>,
+    pub deployed_code_size: Option<u64>,
+    pub state_diff: Option<HashMap<Address, AccountDiff>>,
+    pub struct_logs: Option<Vec<StructLog>>,
+    pub tracer_result: Option<GethTrace>,
+}
+
+/// A transaction committed through the JSON-RPC endpoint of a stateful simulation, along with
+/// its receipt
+#[derive(Debug, Clone)]
+pub struct CommittedTransaction {
+    pub transaction: Transaction,
+    pub receipt: TransactionReceipt,
 }
 
 impl From<CallTraceNode> for CallTrace {
@@ -48,10 +122,48 @@ impl From<CallTraceNode> for CallTrace {
             from: item.trace.caller,
             to: item.trace.address,
             value: item.trace.value,
+            parent: item.parent,
+            children: item.children,
+            depth: item.trace.depth,
+            input: match item.trace.data {
+                RawOrDecodedCall::Raw(data) => data,
+                RawOrDecodedCall::Decoded(..) => Bytes::default(),
+            },
+            output: match item.trace.output {
+                RawOrDecodedReturnData::Raw(output) => output,
+                RawOrDecodedReturnData::Decoded(_) => Bytes::default(),
+            },
+            gas_used: item.trace.gas_cost,
+            success: item.trace.success,
+            status: item.trace.status,
+            function: None,
         }
     }
 }
 
+/// Returns the signature of the function called in the given node, if the decoder was able to
+/// identify it.
+pub fn decoded_function(node: &CallTraceNode) -> Option<String> {
+    match &node.trace.data {
+        RawOrDecodedCall::Decoded(_, signature, _) => Some(signature.clone()),
+        RawOrDecodedCall::Raw(_) => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessListResult {
+    pub access_list: AccessList,
+    pub gas_used_without_access_list: u64,
+    pub gas_used_with_access_list: u64,
+    pub success: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasEstimate {
+    pub gas_used: u64,
+    pub gas_required: u64,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct StorageOverride {
     pub slots: HashMap<Hash, Uint>,
@@ -60,42 +172,37 @@ pub struct StorageOverride {
 
 pub struct Evm {
     executor: Executor,
+    gas_limit: u64,
+    /// Used for transactions which don't set a gas limit of their own
+    default_gas_limit: u64,
     decoder: CallTraceDecoder,
     etherscan_identifier: Option<EtherscanIdentifier>,
+    signature_identifier: Option<SingleSignaturesIdentifier>,
+    transaction_count: u64,
+    /// The gas used by all transactions committed so far, as the session's transactions make up
+    /// a single block
+    cumulative_gas_used: u64,
+    /// The transaction count and cumulative gas used at the time of each snapshot, to restore
+    /// them on revert
+    snapshots: HashMap<u64, (u64, u64)>,
+    /// Transactions committed through the JSON-RPC endpoint, in order
+    committed_transactions: Vec<CommittedTransaction>,
+    /// The chain the EVM runs on, which stays the same when the chain id seen by the `CHAINID`
+    /// opcode is overridden
+    chain_id: u64,
+    /// Whether the block gas limit has been set explicitly, rather than following the gas limit
+    /// of each call
+    block_gas_limit_overridden: bool,
 }
 
 impl Evm {
     pub fn new(
         env: Option<Env>,
-        fork_url: String,
-        fork_block_number: Option<u64>,
+        fork: Fork,
         gas_limit: u64,
         tracing: bool,
-        etherscan_key: Option<String>,
+        chain_config: &ChainConfig,
     ) -> Self {
-        let evm_opts = EvmOpts {
-            fork_url: Some(fork_url.clone()),
-            fork_block_number,
-            env: foundry_evm::executor::opts::Env {
-                chain_id: None,
-                code_size_limit: None,
-                gas_price: Some(0),
-                gas_limit: u64::MAX,
-                ..Default::default()
-            },
-            memory_limit: foundry_config::Config::default().memory_limit,
-            ..Default::default()
-        };
-
-        let fork_opts = CreateFork {
-            url: fork_url,
-            enable_caching: true,
-            env: evm_opts.evm_env_blocking().unwrap(),
-            evm_opts,
-        };
-
-        let db = Backend::spawn(Some(fork_opts.clone()));
-
         let mut builder = ExecutorBuilder::default()
             .with_gas_limit(gas_limit.into())
             .set_tracing(tracing);
@@ -103,72 +210,87 @@ impl Evm {
         if let Some(env) = env {
             builder = builder.with_config(env);
         } else {
-            builder = builder.with_config(fork_opts.env.clone());
+            builder = builder.with_config(fork.env.clone());
         }
 
-        let executor = builder.build(db);
+        let executor = builder.build(fork.backend);
+        let chain_id = executor.env().cfg.chain_id.to::<u64>();
 
-        let foundry_config = foundry_config::Config {
-            etherscan_api_key: etherscan_key,
+        let chain: Chain = fork.env.cfg.chain_id.to::<u64>().into();
+        let mut foundry_config = foundry_config::Config {
+            etherscan_api_key: chain_config.explorer_key.clone(),
             ..Default::default()
         };
+        // Explorers foundry doesn't know about can only be used with an explicit URL
+        if let (Some(url), Some(key)) = (&chain_config.explorer_url, &chain_config.explorer_key) {
+            foundry_config.etherscan = EtherscanConfigs::new([(
+                chain.to_string(),
+                EtherscanConfig {
+                    chain: Some(chain),
+                    url: Some(url.clone()),
+                    key: EtherscanApiKey::Key(key.clone()),
+                },
+            )]);
+        }
 
-        let chain: Chain = fork_opts.env.cfg.chain_id.to::<u64>().into();
         let etherscan_identifier = EtherscanIdentifier::new(&foundry_config, Some(chain)).ok();
         let mut decoder = CallTraceDecoderBuilder::new().with_verbosity(5).build();
 
-        if let Ok(identifier) =
-            SignaturesIdentifier::new(foundry_config::Config::foundry_cache_dir(), false)
-        {
-            decoder.add_signature_identifier(identifier);
+        let signature_identifier =
+            SignaturesIdentifier::new(foundry_config::Config::foundry_cache_dir(), false).ok();
+        if let Some(identifier) = &signature_identifier {
+            decoder.add_signature_identifier(identifier.clone());
         }
 
         Evm {
             executor,
+            gas_limit,
+            default_gas_limit: chain_config.default_gas_limit,
             decoder,
             etherscan_identifier,
+            signature_identifier,
+            transaction_count: 0,
+            cumulative_gas_used: 0,
+            snapshots: HashMap::new(),
+            committed_transactions: Vec::new(),
+            chain_id,
+            block_gas_limit_overridden: false,
         }
     }
 
     pub async fn call_raw(&mut self, call: CallRawRequest) -> Result<CallRawResult, EvmError> {
-        self.set_access_list(call.access_list);
-        let res = self
-            .executor
-            .call_raw(
-                call.from,
-                call.to,
-                call.data.unwrap_or_default().0,
-                call.value.unwrap_or_default(),
-            )
-            .map_err(|err| {
-                dbg!(&err);
-                EvmError(err)
-            })?;
+        self.transact(call, false).await
+    }
 
-        let formatted_trace = if call.format_trace {
-            let mut output = String::new();
-            for trace in &mut res.traces.clone() {
-                if let Some(identifier) = &mut self.etherscan_identifier {
-                    self.decoder.identify(trace, identifier);
-                }
-                self.decoder.decode(trace).await;
-                output.push_str(format!("{trace}").as_str());
+    /// Decodes revert data into a revert reason. Custom errors are looked up in the ABIs of the
+    /// contracts identified through Etherscan, and then in the function signature database.
+    async fn decode_revert(&mut self, data: &[u8]) -> Option<RevertReason> {
+        let reason = RevertReason::decode(data)?;
+        if !matches!(reason, RevertReason::Custom { .. }) {
+            return Some(reason);
+        }
+
+        let known_error = self
+            .decoder
+            .errors
+            .errors
+            .values()
+            .flatten()
+            .find(|error| error.signature()[..4] == data[..4]);
+        if let Some(reason) = known_error.and_then(|error| RevertReason::from_error(error, data)) {
+            return Some(reason);
+        }
+
+        if let Some(identifier) = &self.signature_identifier {
+            let function = identifier.write().await.identify_function(&data[..4]).await;
+            if let Some(reason) =
+                function.and_then(|function| RevertReason::from_function(&function, data))
+            {
+                return Some(reason);
             }
-            Some(output)
-        } else {
-            None
-        };
+        }
 
-        Ok(CallRawResult {
-            gas_used: res.gas_used,
-            block_number: res.env.block.number.to(),
-            success: !res.reverted,
-            trace: res.traces,
-            logs: res.logs,
-            exit_reason: res.exit_reason,
-            return_data: Bytes(res.result),
-            formatted_trace,
-        })
+        Some(reason)
     }
 
     pub fn override_account(
@@ -226,47 +348,459 @@ impl Evm {
         call: CallRawRequest,
         gas_limit: u64,
     ) -> Result<CallRawResult, EvmError> {
-        self.executor.set_gas_limit(gas_limit.into());
-        self.set_access_list(call.access_list);
-        let res = self
-            .executor
-            .call_raw_committing(
-                call.from,
-                call.to,
-                call.data.unwrap_or_default().0,
-                call.value.unwrap_or_default(),
+        self.set_gas_limit(gas_limit);
+        let result = self.transact(call, true).await?;
+        self.transaction_count += 1;
+        self.cumulative_gas_used += result.gas_used;
+        Ok(result)
+    }
+
+    /// Runs the call with an access list inspector to find the addresses and storage slots it
+    /// touches, and compares the gas used without and with the resulting access list, like
+    /// `eth_createAccessList`. Nothing is committed.
+    pub async fn create_access_list(
+        &mut self,
+        call: CallRawRequest,
+    ) -> Result<AccessListResult, EvmError> {
+        let call = CallRawRequest {
+            decode_trace: false,
+            format_trace: false,
+            state_diff: false,
+            trace_config: None,
+            ..call
+        };
+
+        let mut env = self.build_env(&call);
+        let to = match env.tx.transact_to {
+            TransactTo::Call(to) => b160_to_h160(to),
+            // The created address is only known after execution, it's added to the access list
+            // if it's touched again after creation, which is harmless.
+            TransactTo::Create(_) => Address::zero(),
+        };
+        // The sender, recipient and precompiles are always warm, so listing them only costs gas
+        let mut tracer = AccessListTracer::new(
+            call.access_list.clone().unwrap_or_default(),
+            call.from,
+            to,
+            precompiles(),
+        );
+        self.executor
+            .backend_mut()
+            .inspect_ref(&mut env, &mut tracer)
+            .map_err(EvmError)?;
+        let access_list = tracer.access_list();
+
+        let without = self
+            .transact(
+                CallRawRequest {
+                    access_list: None,
+                    ..call.clone()
+                },
+                false,
             )
-            .map_err(|err| {
-                dbg!(&err);
-                EvmError(err)
-            })?;
+            .await?;
+        let with = self
+            .transact(
+                CallRawRequest {
+                    access_list: Some(access_list.clone()),
+                    ..call
+                },
+                false,
+            )
+            .await?;
+
+        Ok(AccessListResult {
+            access_list,
+            gas_used_without_access_list: without.gas_used,
+            gas_used_with_access_list: with.gas_used,
+            success: with.success,
+        })
+    }
+
+    /// Finds the lowest gas limit, up to `gas_limit`, that the call succeeds with by binary
+    /// searching over non-committing executions, like `eth_estimateGas`. Returns `None` if the
+    /// call doesn't succeed even with `gas_limit`.
+    pub async fn estimate_gas(
+        &mut self,
+        call: CallRawRequest,
+        gas_limit: u64,
+    ) -> Result<Option<GasEstimate>, EvmError> {
+        let original_gas_limit = self.gas_limit;
+        let estimate = self.binary_search_gas(call, gas_limit).await;
+        self.set_gas_limit(original_gas_limit);
+        estimate
+    }
+
+    async fn binary_search_gas(
+        &mut self,
+        call: CallRawRequest,
+        gas_limit: u64,
+    ) -> Result<Option<GasEstimate>, EvmError> {
+        let call = CallRawRequest {
+            decode_trace: false,
+            format_trace: false,
+            state_diff: false,
+            trace_config: None,
+            ..call
+        };
+
+        self.set_gas_limit(gas_limit);
+        let result = self.transact(call.clone(), false).await?;
+        if !result.success {
+            return Ok(None);
+        }
+
+        // Refunds are only paid out after execution, so a call can never succeed with a gas
+        // limit below the gas it used, but may need more than that.
+        let mut lo = result.gas_used.saturating_sub(1);
+        let mut hi = gas_limit;
+        while lo + 1 < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.set_gas_limit(mid);
+            // Errors here are gas limits below the intrinsic gas of the call
+            let success = match self.transact(call.clone(), false).await {
+                Ok(result) => result.success,
+                Err(_) => false,
+            };
+            if success {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+
+        Ok(Some(GasEstimate {
+            gas_used: result.gas_used,
+            gas_required: hi,
+        }))
+    }
+
+    /// Executes the call, or deploys a contract if the call has no `to` address, and commits
+    /// the resulting state changes to the backend if `commit` is set.
+    async fn transact(
+        &mut self,
+        call: CallRawRequest,
+        commit: bool,
+    ) -> Result<CallRawResult, EvmError> {
+        let env = self.build_env(&call);
+        if call.realistic_fees {
+            validate_fees(
+                ru256_to_u256(env.block.basefee),
+                ru256_to_u256(env.tx.gas_price),
+                env.tx.gas_priority_fee.map(ru256_to_u256),
+                env.tx.gas_limit,
+                ru256_to_u256(env.tx.value),
+                self.get_balance(call.from)?,
+            )
+            .map_err(|err| EvmError(err.into()))?;
+        }
+
+        // The opcode tracer and the gas given to calls for the call tracer come from a separate
+        // execution of the call, which leaves the state as it was, so that they only slow down
+        // the calls which ask for them
+        let struct_logs = match &call.trace_config {
+            Some(config) if config.tracer.is_none() => Some(
+                self.inspect(&env, StructLogTracer::new(config.clone()))?
+                    .into_logs(),
+            ),
+            _ => None,
+        };
+        let call_gas_limits = match call.trace_config.as_ref().and_then(|config| config.tracer) {
+            Some(GethTracer::CallTracer) => {
+                self.inspect(&env, CallGasTracer::default())?.gas_limits
+            }
+            _ => vec![],
+        };
+        let gas_limit = env.tx.gas_limit;
+
+        let res = self.executor.call_raw_with_env(env).map_err(|err| {
+            log::debug!(target: "ts::evm", "Failed to execute the call: {err:#}");
+            EvmError(err)
+        })?;
+
+        // Diffing has to happen before committing, while the backend still has the old state
+        let state_diff = match &res.state_changeset {
+            Some(changeset) if call.state_diff => {
+                Some(state_diff(self.executor.backend(), changeset))
+            }
+            _ => None,
+        };
+        let tracer_result = match &call.trace_config {
+            Some(TraceConfig {
+                tracer: Some(tracer),
+                tracer_config,
+                ..
+            }) => match tracer {
+                GethTracer::CallTracer => Some(GethTrace::CallTracer(call_frame(
+                    &res.traces.clone().unwrap_or_default(),
+                    &call_gas_limits,
+                    tracer_config,
+                    gas_limit,
+                    res.gas_used,
+                ))),
+                GethTracer::PrestateTracer => res.state_changeset.as_ref().map(|changeset| {
+                    prestate(self.executor.backend(), changeset, tracer_config.diff_mode)
+                }),
+                GethTracer::FourByteTracer => Some(GethTrace::FourByteTracer(four_bytes(
+                    &res.traces.clone().unwrap_or_default(),
+                ))),
+            },
+            _ => None,
+        };
+
+        if commit {
+            if let Some(changeset) = res.state_changeset.clone() {
+                self.executor.backend_mut().commit(changeset);
+            }
+        }
 
-        let formatted_trace = if call.format_trace {
+        let (formatted_trace, decoded_trace) = if call.decode_trace || call.format_trace {
             let mut output = String::new();
-            for trace in &mut res.traces.clone() {
+            let mut decoded_trace = res.traces.clone();
+            for trace in &mut decoded_trace {
                 if let Some(identifier) = &mut self.etherscan_identifier {
                     self.decoder.identify(trace, identifier);
                 }
                 self.decoder.decode(trace).await;
                 output.push_str(format!("{trace}").as_str());
             }
-            Some(output)
+            (call.format_trace.then_some(output), decoded_trace)
+        } else {
+            (None, None)
+        };
+
+        let revert_reason = if res.reverted {
+            self.decode_revert(&res.result).await
         } else {
             None
         };
 
+        let (deployed_address, deployed_code_size) = match &res.out {
+            Some(Output::Create(code, address)) if !res.reverted => {
+                (address.map(b160_to_h160), Some(code.len() as u64))
+            }
+            _ => (None, None),
+        };
+
         Ok(CallRawResult {
             gas_used: res.gas_used,
             block_number: res.env.block.number.to(),
             success: !res.reverted,
             trace: res.traces,
+            decoded_trace,
             logs: res.logs,
             exit_reason: res.exit_reason,
             return_data: Bytes(res.result),
             formatted_trace,
+            revert_reason,
+            deployed_address,
+            deployed_code_size,
+            state_diff,
+            struct_logs,
+            tracer_result,
         })
     }
 
+    /// Runs the call in the given environment with an inspector, without committing it
+    fn inspect<I: Inspector<Backend>>(
+        &mut self,
+        env: &Env,
+        mut inspector: I,
+    ) -> Result<I, EvmError> {
+        self.executor
+            .backend_mut()
+            .inspect_ref(&mut env.clone(), &mut inspector)
+            .map_err(EvmError)?;
+        Ok(inspector)
+    }
+
+    /// Builds the environment to execute the call in. Like foundry's test environments, the
+    /// base fee is zeroed so that calls don't need a gas price, unless it has been overridden or
+    /// the call is charged realistic fees.
+    fn build_env(&mut self, call: &CallRawRequest) -> Env {
+        self.set_access_list(call.access_list.clone());
+
+        let mut env = self.executor.env().clone();
+        if call.realistic_fees {
+            env.cfg.disable_base_fee = false;
+            env.tx.gas_price = u256_to_ru256(charged_gas_price(
+                ru256_to_u256(env.block.basefee),
+                call.gas_price,
+                call.max_fee_per_gas,
+                call.max_priority_fee_per_gas,
+            ));
+            env.tx.gas_priority_fee = call.max_priority_fee_per_gas.map(u256_to_ru256);
+        } else if !env.cfg.disable_base_fee {
+            env.block.basefee = rU256::ZERO;
+        }
+        env.tx.caller = h160_to_b160(call.from);
+        env.tx.transact_to = match call.to {
+            Some(to) => TransactTo::Call(h160_to_b160(to)),
+            None => TransactTo::Create(CreateScheme::Create),
+        };
+        env.tx.data = call.data.clone().unwrap_or_default().0;
+        env.tx.value = u256_to_ru256(call.value.unwrap_or_default());
+        env.tx.gas_limit = self.gas_limit;
+        env.tx.nonce = call.nonce;
+        // Like foundry's test environments, calls may use more gas than the block they're in
+        if !self.block_gas_limit_overridden {
+            env.block.gas_limit = rU256::from(self.gas_limit);
+        }
+        env
+    }
+
+    /// Snapshots the current state and block environment, returning the id to revert to.
+    pub fn snapshot(&mut self) -> u64 {
+        let env = self.executor.env().clone();
+        let snapshot_id = self
+            .executor
+            .backend_mut()
+            .snapshot(&JournaledState::new(0), &env)
+            .as_u64();
+        self.snapshots.insert(
+            snapshot_id,
+            (self.transaction_count, self.cumulative_gas_used),
+        );
+        snapshot_id
+    }
+
+    /// Reverts the state and block environment to a snapshot. Like `evm_revert`, this consumes
+    /// the snapshot and any snapshots taken after it. Returns `false` if there's no such snapshot.
+    pub fn revert(&mut self, snapshot_id: u64) -> bool {
+        let mut env = self.executor.env().clone();
+        let reverted = self
+            .executor
+            .backend_mut()
+            .revert(Uint::from(snapshot_id), &JournaledState::new(0), &mut env)
+            .is_some();
+        if reverted {
+            *self.executor.env_mut() = env;
+            if let Some(&(transaction_count, cumulative_gas_used)) =
+                self.snapshots.get(&snapshot_id)
+            {
+                self.transaction_count = transaction_count;
+                self.cumulative_gas_used = cumulative_gas_used;
+                self.committed_transactions.retain(|committed| {
+                    committed.receipt.transaction_index.as_u64() < transaction_count
+                });
+            }
+            self.snapshots.retain(|id, _| *id < snapshot_id);
+        }
+        reverted
+    }
+
+    /// Exports the accounts changed in this EVM along with its block environment, so that the
+    /// state can be loaded into another EVM forked from the same block
+    pub fn dump_state(&self) -> StateDump {
+        let backend = self.executor.backend();
+        let accounts = match backend.active_fork_db() {
+            Some(db) => changed_accounts(db),
+            None => changed_accounts(backend.mem_db()),
+        };
+
+        StateDump {
+            block: Some(DumpBlock::from(&self.executor.env().block)),
+            accounts,
+        }
+    }
+
+    /// Commits the accounts of a state dump, and takes on its block environment
+    pub fn load_state(&mut self, dump: &StateDump) {
+        self.executor.backend_mut().commit(dump.changes());
+        if let Some(block) = &dump.block {
+            block.apply(&mut self.executor.env_mut().block);
+        }
+    }
+
+    /// Whether the contract implements the interface according to its ERC-165
+    /// `supportsInterface`. Contracts without it don't implement any.
+    pub fn supports_interface(&self, address: Address, interface_id: [u8; 4]) -> bool {
+        let calldata = [
+            ethers::utils::id("supportsInterface(bytes4)").to_vec(),
+            encode(&[Token::FixedBytes(interface_id.to_vec())]),
+        ]
+        .concat();
+        self.executor
+            .call_raw(
+                B160::zero(),
+                h160_to_b160(address),
+                calldata.into(),
+                rU256::ZERO,
+            )
+            .map_or(false, |res| {
+                !res.reverted && res.result.len() == 32 && res.result[31] == 1
+            })
+    }
+
+    /// The number of transactions committed to this EVM
+    pub fn get_transaction_count(&self) -> u64 {
+        self.transaction_count
+    }
+
+    pub fn get_cumulative_gas_used(&self) -> u64 {
+        self.cumulative_gas_used
+    }
+
+    /// Keeps a transaction committed through the JSON-RPC endpoint. Its receipt's transaction
+    /// index must be its position among the transactions committed to this EVM.
+    pub fn record_transaction(&mut self, committed: CommittedTransaction) {
+        self.committed_transactions.push(committed);
+    }
+
+    pub fn get_committed_transaction(&self, hash: Hash) -> Option<&CommittedTransaction> {
+        self.committed_transactions
+            .iter()
+            .find(|committed| committed.transaction.hash == hash)
+    }
+
+    pub fn get_balance(&self, address: Address) -> Result<Uint, EvmError> {
+        Ok(self
+            .get_account(address)?
+            .map(|info| ru256_to_u256(info.balance))
+            .unwrap_or_default())
+    }
+
+    pub fn get_nonce(&self, address: Address) -> Result<u64, EvmError> {
+        Ok(self
+            .get_account(address)?
+            .map(|info| info.nonce)
+            .unwrap_or_default())
+    }
+
+    pub fn get_code(&self, address: Address) -> Result<Bytes, EvmError> {
+        let Some(info) = self.get_account(address)? else {
+            return Ok(Bytes::default());
+        };
+        let code = match info.code {
+            Some(code) => code,
+            None => self
+                .executor
+                .backend()
+                .code_by_hash(info.code_hash)
+                .map_err(|err| EvmError(err.into()))?,
+        };
+        Ok(Bytes(code.original_bytes()))
+    }
+
+    pub fn get_storage_at(&self, address: Address, slot: Hash) -> Result<Hash, EvmError> {
+        let value = self
+            .executor
+            .backend()
+            .storage(
+                h160_to_b160(address),
+                u256_to_ru256(Uint::from_big_endian(slot.as_bytes())),
+            )
+            .map_err(|err| EvmError(err.into()))?;
+        Ok(Hash::from(value.to_be_bytes::<32>()))
+    }
+
+    fn get_account(&self, address: Address) -> Result<Option<AccountInfo>, EvmError> {
+        self.executor
+            .backend()
+            .basic(h160_to_b160(address))
+            .map_err(|err| EvmError(err.into()))
+    }
+
     pub async fn set_block(&mut self, number: u64) -> Result<(), EvmError> {
         self.executor.env_mut().block.number = Uint::from(number).into();
         Ok(())
@@ -285,8 +819,86 @@ impl Evm {
         self.executor.env().block.timestamp.into()
     }
 
+    pub fn set_coinbase(&mut self, coinbase: Address) {
+        self.executor.env_mut().block.coinbase = h160_to_b160(coinbase);
+    }
+
+    pub fn get_coinbase(&self) -> Address {
+        b160_to_h160(self.executor.env().block.coinbase)
+    }
+
+    /// Sets the base fee seen by the `BASEFEE` opcode. Calls are still executed without a gas
+    /// price, so the base fee check is disabled.
+    pub fn set_base_fee(&mut self, base_fee: Uint) {
+        let env = self.executor.env_mut();
+        env.block.basefee = u256_to_ru256(base_fee);
+        env.cfg.disable_base_fee = true;
+    }
+
+    /// The base fee of the block, which calls are only charged with realistic fees
+    pub fn get_block_base_fee(&self) -> Uint {
+        self.executor.env().block.basefee.into()
+    }
+
+    /// The base fee calls are executed with, which is zero unless it has been overridden
+    pub fn get_base_fee(&self) -> Uint {
+        let env = self.executor.env();
+        if env.cfg.disable_base_fee {
+            env.block.basefee.into()
+        } else {
+            Uint::zero()
+        }
+    }
+
+    pub fn set_prevrandao(&mut self, prevrandao: Hash) {
+        let env = self.executor.env_mut();
+        env.block.prevrandao = Some(h256_to_b256(prevrandao));
+        env.block.difficulty = u256_to_ru256(Uint::from_big_endian(prevrandao.as_bytes()));
+    }
+
+    pub fn set_difficulty(&mut self, difficulty: Uint) {
+        self.executor.env_mut().block.difficulty = u256_to_ru256(difficulty);
+    }
+
+    pub fn get_prevrandao(&self) -> Hash {
+        self.executor
+            .env()
+            .block
+            .prevrandao
+            .map(b256_to_h256)
+            .unwrap_or_default()
+    }
+
+    pub fn set_block_gas_limit(&mut self, gas_limit: u64) {
+        self.executor.env_mut().block.gas_limit = Uint::from(gas_limit).into();
+        self.block_gas_limit_overridden = true;
+    }
+
+    pub fn get_block_gas_limit(&self) -> u64 {
+        self.executor.env().block.gas_limit.saturating_to()
+    }
+
+    pub fn set_gas_limit(&mut self, gas_limit: u64) {
+        self.gas_limit = gas_limit;
+        self.executor.set_gas_limit(gas_limit.into());
+    }
+
+    pub fn get_gas_limit(&self) -> u64 {
+        self.gas_limit
+    }
+
+    pub fn get_default_gas_limit(&self) -> u64 {
+        self.default_gas_limit
+    }
+
     pub fn get_chain_id(&self) -> Uint {
-        self.executor.env().cfg.chain_id.into()
+        self.chain_id.into()
+    }
+
+    /// Sets the chain id seen by the `CHAINID` opcode. Requests are still checked against the
+    /// chain the EVM runs on.
+    pub fn set_chain_id_override(&mut self, chain_id: u64) {
+        self.executor.env_mut().cfg.chain_id = rU256::from(chain_id);
     }
 
     fn set_access_list(&mut self, access_list: Option<AccessList>) {
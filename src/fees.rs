@@ -0,0 +1,196 @@
+use std::fmt;
+
+use ethers::abi::Uint;
+use serde::{Deserialize, Serialize};
+
+/// What a transaction pays per unit of gas, like the `effectiveGasPrice` of a receipt. EIP-1559
+/// transactions pay the base fee plus their priority fee, capped at their max fee, and legacy
+/// transactions pay their gas price.
+pub fn effective_gas_price(
+    base_fee: Uint,
+    gas_price: Option<Uint>,
+    max_fee_per_gas: Option<Uint>,
+    max_priority_fee_per_gas: Option<Uint>,
+) -> Uint {
+    match max_fee_per_gas {
+        Some(max_fee_per_gas) => max_fee_per_gas
+            .min(base_fee.saturating_add(max_priority_fee_per_gas.unwrap_or_default())),
+        None => gas_price.unwrap_or_default(),
+    }
+}
+
+/// The gas price a transaction charged realistic fees pays at most: its max fee, or its gas
+/// price. Transactions without either pay the base fee plus their priority fee.
+pub fn charged_gas_price(
+    base_fee: Uint,
+    gas_price: Option<Uint>,
+    max_fee_per_gas: Option<Uint>,
+    max_priority_fee_per_gas: Option<Uint>,
+) -> Uint {
+    max_fee_per_gas
+        .or(gas_price)
+        .unwrap_or_else(|| base_fee.saturating_add(max_priority_fee_per_gas.unwrap_or_default()))
+}
+
+/// Why a transaction charged realistic fees can't be included in a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeError {
+    InsufficientFundsForGas,
+    MaxFeePerGasLessThanBaseFee,
+    PriorityFeeGreaterThanMaxFee,
+}
+
+impl fmt::Display for FeeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            FeeError::InsufficientFundsForGas => "insufficient funds for gas * price + value",
+            FeeError::MaxFeePerGasLessThanBaseFee => "max fee per gas less than block base fee",
+            FeeError::PriorityFeeGreaterThanMaxFee => {
+                "max priority fee per gas higher than max fee per gas"
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for FeeError {}
+
+/// Checks that a transaction can pay for its gas, in the same order as the EVM does before
+/// executing it. The sender has to afford its whole gas limit at its max fee, plus the value.
+pub fn validate_fees(
+    base_fee: Uint,
+    gas_price: Uint,
+    max_priority_fee_per_gas: Option<Uint>,
+    gas_limit: u64,
+    value: Uint,
+    balance: Uint,
+) -> Result<(), FeeError> {
+    if max_priority_fee_per_gas.map_or(false, |priority_fee| priority_fee > gas_price) {
+        return Err(FeeError::PriorityFeeGreaterThanMaxFee);
+    }
+    if gas_price < base_fee {
+        return Err(FeeError::MaxFeePerGasLessThanBaseFee);
+    }
+    let cost = gas_price
+        .checked_mul(Uint::from(gas_limit))
+        .and_then(|cost| cost.checked_add(value));
+    if cost.map_or(true, |cost| cost > balance) {
+        return Err(FeeError::InsufficientFundsForGas);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Fees {
+    pub effective_gas_price: Uint,
+    /// The total paid by the sender for the gas used
+    pub total_fee: Uint,
+    /// The part of the total fee which goes to the coinbase, the rest is burnt
+    pub coinbase_tip: Uint,
+}
+
+impl Fees {
+    pub fn new(base_fee: Uint, effective_gas_price: Uint, gas_used: u64) -> Self {
+        let gas_used = Uint::from(gas_used);
+        Fees {
+            effective_gas_price,
+            total_fee: effective_gas_price.saturating_mul(gas_used),
+            coinbase_tip: effective_gas_price
+                .saturating_sub(base_fee)
+                .saturating_mul(gas_used),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective_gas_price() {
+        let base_fee = Uint::from(100);
+
+        // Legacy transactions pay their gas price
+        assert_eq!(
+            effective_gas_price(base_fee, Some(Uint::from(150)), None, None),
+            Uint::from(150)
+        );
+        // EIP-1559 transactions pay the base fee plus their priority fee
+        assert_eq!(
+            effective_gas_price(base_fee, None, Some(Uint::from(200)), Some(Uint::from(2))),
+            Uint::from(102)
+        );
+        // Up to their max fee
+        assert_eq!(
+            effective_gas_price(base_fee, None, Some(Uint::from(101)), Some(Uint::from(2))),
+            Uint::from(101)
+        );
+        assert_eq!(
+            effective_gas_price(base_fee, None, None, None),
+            Uint::zero()
+        );
+    }
+
+    #[test]
+    fn test_charged_gas_price() {
+        let base_fee = Uint::from(100);
+
+        assert_eq!(
+            charged_gas_price(base_fee, Some(Uint::from(150)), None, None),
+            Uint::from(150)
+        );
+        assert_eq!(
+            charged_gas_price(base_fee, None, Some(Uint::from(200)), Some(Uint::from(2))),
+            Uint::from(200)
+        );
+        // Without fees the transaction pays the base fee plus its priority fee
+        assert_eq!(charged_gas_price(base_fee, None, None, None), base_fee);
+        assert_eq!(
+            charged_gas_price(base_fee, None, None, Some(Uint::from(2))),
+            Uint::from(102)
+        );
+    }
+
+    #[test]
+    fn test_validate_fees() {
+        let base_fee = Uint::from(100);
+        let balance = Uint::from(100 * 21000 + 1);
+
+        assert_eq!(
+            validate_fees(base_fee, base_fee, None, 21000, Uint::one(), balance),
+            Ok(())
+        );
+        assert_eq!(
+            validate_fees(base_fee, base_fee, None, 21000, Uint::from(2), balance),
+            Err(FeeError::InsufficientFundsForGas)
+        );
+        assert_eq!(
+            validate_fees(base_fee, Uint::from(99), None, 21000, Uint::zero(), balance),
+            Err(FeeError::MaxFeePerGasLessThanBaseFee)
+        );
+        assert_eq!(
+            validate_fees(
+                base_fee,
+                base_fee,
+                Some(Uint::from(101)),
+                21000,
+                Uint::zero(),
+                balance
+            ),
+            Err(FeeError::PriorityFeeGreaterThanMaxFee)
+        );
+        assert_eq!(
+            validate_fees(base_fee, Uint::MAX, None, 21000, Uint::zero(), balance),
+            Err(FeeError::InsufficientFundsForGas)
+        );
+    }
+
+    #[test]
+    fn test_fees() {
+        let fees = Fees::new(Uint::from(100), Uint::from(102), 21000);
+
+        assert_eq!(fees.total_fee, Uint::from(102 * 21000));
+        assert_eq!(fees.coinbase_tip, Uint::from(2 * 21000));
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use foundry_evm::executor::fork::CreateFork;
+use foundry_evm::executor::{opts::EvmOpts, Backend};
+use revm::primitives::{Env, U256 as rU256};
+use revm::DatabaseCommit;
+
+use crate::chains::LocalChain;
+use crate::errors::RpcError;
+
+/// Identifies a forked backend: forks of the same chain, from the same RPC, at the same block
+/// can share everything that has been fetched from the RPC so far.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ForkKey {
+    pub chain_id: u64,
+    pub fork_url: String,
+    pub block_number: u64,
+}
+
+/// A forked backend together with the environment of the block it was forked at.
+///
+/// Cloning a `Fork` is cheap: the clone gets its own in-memory state on top of the fork, while
+/// the RPC cache underneath is shared with every other clone of the same fork.
+#[derive(Clone)]
+pub struct Fork {
+    pub backend: Backend,
+    pub env: Env,
+}
+
+impl Fork {
+    /// Spawns a new forked backend. If `fork_block_number` is `None` the latest block is used,
+    /// and the fork is pinned to it so that all state is read from the same block.
+    ///
+    /// With `rpc_cache`, foundry keeps what the fork fetches on disk.
+    pub fn spawn(
+        fork_url: String,
+        fork_block_number: Option<u64>,
+        rpc_cache: bool,
+    ) -> Result<Self, RpcError> {
+        let mut evm_opts = fork_evm_opts(fork_url.clone(), fork_block_number);
+        let env = evm_opts.evm_env_blocking().map_err(RpcError)?;
+        evm_opts.fork_block_number = Some(env.block.number.to());
+
+        Ok(Self::spawn_with_env(fork_url, evm_opts, env, rpc_cache))
+    }
+
+    fn spawn_with_env(fork_url: String, evm_opts: EvmOpts, env: Env, rpc_cache: bool) -> Self {
+        let fork_opts = CreateFork {
+            url: fork_url,
+            enable_caching: rpc_cache,
+            env: env.clone(),
+            evm_opts,
+        };
+
+        Fork {
+            backend: Backend::spawn(Some(fork_opts)),
+            env,
+        }
+    }
+
+    /// Creates a backend which isn't forked from anything, holding the state of a local chain
+    pub fn local(chain_id: u64, chain: &LocalChain, default_gas_limit: u64) -> Self {
+        let mut backend = Backend::spawn(None);
+        backend.commit(chain.dump.changes());
+
+        Fork {
+            backend,
+            env: local_env(chain_id, chain, default_gas_limit),
+        }
+    }
+
+    pub fn block_number(&self) -> u64 {
+        self.env.block.number.to()
+    }
+}
+
+fn local_env(chain_id: u64, chain: &LocalChain, default_gas_limit: u64) -> Env {
+    let mut env = Env::default();
+    env.cfg.chain_id = rU256::from(chain_id);
+    env.block.gas_limit = rU256::from(default_gas_limit);
+    // The block environment of the config takes precedence over the one of the state file
+    if let Some(block) = &chain.dump.block {
+        block.apply(&mut env.block);
+    }
+    chain.block().apply(&mut env.block);
+    env
+}
+
+fn fork_evm_opts(fork_url: String, fork_block_number: Option<u64>) -> EvmOpts {
+    EvmOpts {
+        fork_url: Some(fork_url),
+        fork_block_number,
+        env: foundry_evm::executor::opts::Env {
+            chain_id: None,
+            code_size_limit: None,
+            gas_price: Some(0),
+            gas_limit: u64::MAX,
+            ..Default::default()
+        },
+        memory_limit: foundry_config::Config::default().memory_limit,
+        ..Default::default()
+    }
+}
+
+struct PooledFork {
+    fork: Fork,
+    last_used: Instant,
+}
+
+/// A pool of forked backends shared across requests, so that requests against the same block
+/// start with a warm RPC cache instead of re-fetching the same accounts and slots.
+///
+/// Forks are evicted least recently used first once the pool holds `capacity` forks. A
+/// capacity of 0 disables pooling and every request gets a freshly spawned fork.
+///
+/// The backends of local chains are kept apart from the forks, and are never evicted.
+pub struct ForkPool {
+    capacity: usize,
+    rpc_cache: bool,
+    forks: Mutex<HashMap<ForkKey, PooledFork>>,
+    locals: Mutex<HashMap<u64, Fork>>,
+}
+
+impl ForkPool {
+    pub fn new(capacity: usize) -> Self {
+        ForkPool {
+            capacity,
+            rpc_cache: true,
+            forks: Mutex::new(HashMap::new()),
+            locals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether forks keep foundry's RPC cache on disk, which only covers forks at a fixed block
+    /// and isn't needed alongside the fork cache
+    pub fn with_rpc_cache(mut self, rpc_cache: bool) -> Self {
+        self.rpc_cache = rpc_cache;
+        self
+    }
+
+    /// Returns a copy-on-write clone of the pooled fork for the given chain, URL and block,
+    /// spawning and pooling a new fork if there is none yet.
+    pub fn fork(
+        &self,
+        chain_id: u64,
+        fork_url: String,
+        fork_block_number: Option<u64>,
+    ) -> Result<Fork, RpcError> {
+        if self.capacity == 0 {
+            return Fork::spawn(fork_url, fork_block_number, self.rpc_cache);
+        }
+
+        // Without a block number the latest block has to be resolved first, which also gives
+        // us the environment in case the fork isn't pooled yet.
+        let (block_number, env) = match fork_block_number {
+            Some(block_number) => (block_number, None),
+            None => {
+                let evm_opts = fork_evm_opts(fork_url.clone(), None);
+                let env = evm_opts.evm_env_blocking().map_err(RpcError)?;
+                (env.block.number.to(), Some((evm_opts, env)))
+            }
+        };
+
+        let key = ForkKey {
+            chain_id,
+            fork_url,
+            block_number,
+        };
+
+        if let Some(fork) = self.get(&key) {
+            return Ok(fork);
+        }
+
+        // The lock isn't held while spawning, as that involves RPC round-trips. If two requests
+        // race to spawn the same fork, the last one to finish is pooled.
+        let fork = match env {
+            Some((mut evm_opts, env)) => {
+                evm_opts.fork_block_number = Some(block_number);
+                Fork::spawn_with_env(key.fork_url.clone(), evm_opts, env, self.rpc_cache)
+            }
+            None => Fork::spawn(key.fork_url.clone(), Some(block_number), self.rpc_cache)?,
+        };
+
+        self.insert(key, fork.clone());
+
+        Ok(fork)
+    }
+
+    /// Returns a copy-on-write clone of the backend of a local chain, creating it on first use.
+    /// Local chains have no history, so a block number only changes the block environment.
+    pub fn local(
+        &self,
+        chain_id: u64,
+        chain: &LocalChain,
+        default_gas_limit: u64,
+        block_number: Option<u64>,
+    ) -> Fork {
+        let mut fork = self
+            .locals
+            .lock()
+            .unwrap()
+            .entry(chain_id)
+            .or_insert_with(|| Fork::local(chain_id, chain, default_gas_limit))
+            .clone();
+        if let Some(block_number) = block_number {
+            fork.env.block.number = rU256::from(block_number);
+        }
+        fork
+    }
+
+    pub fn len(&self) -> usize {
+        self.forks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &ForkKey) -> Option<Fork> {
+        let mut forks = self.forks.lock().unwrap();
+        let pooled = forks.get_mut(key)?;
+        pooled.last_used = Instant::now();
+        Some(pooled.fork.clone())
+    }
+
+    fn insert(&self, key: ForkKey, fork: Fork) {
+        let mut forks = self.forks.lock().unwrap();
+
+        while forks.len() >= self.capacity && !forks.contains_key(&key) {
+            let Some(oldest) = forks
+                .iter()
+                .min_by_key(|(_, pooled)| pooled.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            log::debug!(target: "ts::fork", "Evicting fork {:?}", oldest);
+            forks.remove(&oldest);
+        }
+
+        forks.insert(
+            key,
+            PooledFork {
+                fork,
+                last_used: Instant::now(),
+            },
+        );
+    }
+}
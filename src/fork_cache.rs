@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use ethers::abi::{Address, Uint};
+use ethers::types::U64;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::reply::Json;
+use warp::Rejection;
+
+use crate::errors::ForkCacheDisabledError;
+use crate::SharedSimulationState;
+
+/// How often new entries are written to disk
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ForkCacheStats {
+    pub blocks: usize,
+    pub size_bytes: u64,
+    pub max_size_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ForkCachePurgeResponse {
+    pub purged_blocks: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BlockKey {
+    chain_id: u64,
+    block_number: u64,
+}
+
+/// The part of the state of a block that a cacheable request asks for
+#[derive(Debug, Clone, PartialEq)]
+enum Entry {
+    Balance(Address),
+    Nonce(Address),
+    Code(Address),
+    Storage(Address, Uint),
+}
+
+/// The results of the requests made against a block, as returned by the RPC
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BlockState {
+    balances: HashMap<Address, Value>,
+    nonces: HashMap<Address, Value>,
+    code: HashMap<Address, Value>,
+    storage: HashMap<Address, HashMap<Uint, Value>>,
+}
+
+impl BlockState {
+    fn get(&self, entry: &Entry) -> Option<&Value> {
+        match entry {
+            Entry::Balance(address) => self.balances.get(address),
+            Entry::Nonce(address) => self.nonces.get(address),
+            Entry::Code(address) => self.code.get(address),
+            Entry::Storage(address, slot) => self.storage.get(address)?.get(slot),
+        }
+    }
+
+    fn insert(&mut self, entry: Entry, value: Value) {
+        match entry {
+            Entry::Balance(address) => self.balances.insert(address, value),
+            Entry::Nonce(address) => self.nonces.insert(address, value),
+            Entry::Code(address) => self.code.insert(address, value),
+            Entry::Storage(address, slot) => {
+                self.storage.entry(address).or_default().insert(slot, value)
+            }
+        };
+    }
+}
+
+struct CachedBlock {
+    /// Tells the block apart from a block cached under the same key after this one was removed
+    id: u64,
+    /// Blocks cached by an earlier run are only read from disk once they are used
+    state: Option<BlockState>,
+    /// The size on disk, or an estimate for entries which haven't been written yet
+    size: u64,
+    last_used: SystemTime,
+    dirty: bool,
+    /// Counts the entries inserted, so that a block which got new entries while it was being
+    /// written stays dirty
+    inserts: u64,
+}
+
+/// A cache on disk of the account info, code and storage fetched by forks, by chain and block.
+///
+/// Forks at the same block share the same state no matter when they are spawned, so the cache
+/// outlives restarts. Once the cache grows over its maximum size, the least recently used blocks
+/// are evicted.
+pub struct ForkCache {
+    dir: PathBuf,
+    max_size: u64,
+    blocks: Mutex<HashMap<BlockKey, CachedBlock>>,
+    next_id: AtomicU64,
+    /// Held while flushing, so that two flushes don't write the same files at once
+    flushing: Mutex<()>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ForkCache {
+    /// Opens the cache in `dir`, picking up the blocks cached there by earlier runs
+    pub fn open(dir: PathBuf, max_size: u64) -> eyre::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut blocks = HashMap::new();
+        let mut next_id = 0;
+        for chain_dir in std::fs::read_dir(&dir)? {
+            let chain_dir = chain_dir?;
+            if !chain_dir.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(chain_id) = parse_file_name(&chain_dir.path(), None) else {
+                continue;
+            };
+            for file in std::fs::read_dir(chain_dir.path())? {
+                let file = file?;
+                let Some(block_number) = parse_file_name(&file.path(), Some("json")) else {
+                    continue;
+                };
+                let metadata = file.metadata()?;
+                blocks.insert(
+                    BlockKey {
+                        chain_id,
+                        block_number,
+                    },
+                    CachedBlock {
+                        id: next_id,
+                        state: None,
+                        size: metadata.len(),
+                        last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                        dirty: false,
+                        inserts: 0,
+                    },
+                );
+                next_id += 1;
+            }
+        }
+
+        let cache = ForkCache {
+            dir,
+            max_size,
+            blocks: Mutex::new(blocks),
+            next_id: AtomicU64::new(next_id),
+            flushing: Mutex::new(()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        };
+        // The maximum size may have been lowered since the last run
+        let evicted = cache.evict(&mut cache.blocks.lock().unwrap(), None);
+        for path in cache.paths(&evicted) {
+            remove_file(&path);
+        }
+
+        Ok(cache)
+    }
+
+    /// Answers a JSON-RPC request from the cache, if it asks for state at a fixed block which has
+    /// been fetched before
+    pub async fn get(&self, chain_id: u64, request: &[u8]) -> Option<Vec<u8>> {
+        let request: Value = serde_json::from_slice(request).ok()?;
+        let (block_number, entry) = parse_request(&request)?;
+        let key = BlockKey {
+            chain_id,
+            block_number,
+        };
+
+        self.load(key).await;
+        let result = self
+            .blocks
+            .lock()
+            .unwrap()
+            .get(&key)
+            .and_then(|block| block.state.as_ref()?.get(&entry).cloned());
+        let Some(result) = result else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        serde_json::to_vec(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": result,
+        }))
+        .ok()
+    }
+
+    /// Caches the response to a JSON-RPC request, if it asked for state at a fixed block and
+    /// succeeded
+    pub async fn insert(&self, chain_id: u64, request: &[u8], response: &[u8]) {
+        let Ok(request) = serde_json::from_slice::<Value>(request) else {
+            return;
+        };
+        let Some((block_number, entry)) = parse_request(&request) else {
+            return;
+        };
+        let Ok(mut response) = serde_json::from_slice::<Value>(response) else {
+            return;
+        };
+        let Some(result) = response.get_mut("result").map(Value::take) else {
+            return;
+        };
+        let key = BlockKey {
+            chain_id,
+            block_number,
+        };
+
+        self.load(key).await;
+        let evicted = {
+            let mut blocks = self.blocks.lock().unwrap();
+            let block = blocks.entry(key).or_insert_with(|| CachedBlock {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                state: Some(BlockState::default()),
+                size: 0,
+                last_used: SystemTime::now(),
+                dirty: false,
+                inserts: 0,
+            });
+            // Roughly what the entry takes up on disk, until the block is written
+            block.size += result.to_string().len() as u64 + 80;
+            block.dirty = true;
+            block.inserts += 1;
+            if let Some(state) = &mut block.state {
+                state.insert(entry, result);
+            }
+
+            self.evict(&mut blocks, Some(key))
+        };
+        self.remove_files(&evicted).await;
+    }
+
+    /// Writes the blocks with new entries to disk, on a blocking thread
+    pub async fn flush(self: &Arc<Self>) -> eyre::Result<()> {
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || cache.write_dirty_blocks()).await?
+    }
+
+    fn write_dirty_blocks(&self) -> eyre::Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+
+        let mut writes = Vec::new();
+        {
+            let blocks = self.blocks.lock().unwrap();
+            for (key, block) in blocks.iter().filter(|(_, block)| block.dirty) {
+                let Some(state) = &block.state else {
+                    continue;
+                };
+                let contents = serde_json::to_vec(state)?;
+                writes.push((*key, block.id, block.inserts, contents));
+            }
+        }
+
+        // Written outside of the lock, so that requests aren't held up by the disk
+        for (key, id, inserts, contents) in writes {
+            let path = self.path(&key);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let temp_path = path.with_extension("json.tmp");
+            let size = contents.len() as u64;
+            std::fs::write(&temp_path, contents)?;
+            // Renaming is atomic, so a crash mid-write doesn't leave a corrupt block behind
+            std::fs::rename(temp_path, &path)?;
+
+            let removed = {
+                let mut blocks = self.blocks.lock().unwrap();
+                match blocks.get_mut(&key).filter(|block| block.id == id) {
+                    Some(block) => {
+                        block.size = size;
+                        block.dirty = block.inserts != inserts;
+                        false
+                    }
+                    None => true,
+                }
+            };
+            // The block was purged or evicted while it was written, and its file may have been
+            // removed before the rename brought it back
+            if removed {
+                remove_file(&path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes every cached block of the chain, or of all chains, returning how many were removed
+    pub async fn purge(&self, chain_id: Option<u64>) -> usize {
+        let purged: Vec<BlockKey> = {
+            let mut blocks = self.blocks.lock().unwrap();
+            let purged: Vec<BlockKey> = blocks
+                .keys()
+                .filter(|key| chain_id.map_or(true, |chain_id| key.chain_id == chain_id))
+                .copied()
+                .collect();
+            for key in &purged {
+                blocks.remove(key);
+            }
+            purged
+        };
+        self.remove_files(&purged).await;
+        purged.len()
+    }
+
+    pub fn stats(&self) -> ForkCacheStats {
+        let blocks = self.blocks.lock().unwrap();
+        ForkCacheStats {
+            blocks: blocks.len(),
+            size_bytes: blocks.values().map(|block| block.size).sum(),
+            max_size_bytes: self.max_size,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Marks the block as used, and reads its state from disk if it hasn't been used since
+    /// startup. The file is read outside of the lock, so that other requests aren't held up by
+    /// the disk.
+    async fn load(&self, key: BlockKey) {
+        let id = {
+            let mut blocks = self.blocks.lock().unwrap();
+            let Some(block) = blocks.get_mut(&key) else {
+                return;
+            };
+            block.last_used = SystemTime::now();
+            if block.state.is_some() {
+                return;
+            }
+            block.id
+        };
+
+        let path = self.path(&key);
+        let state = tokio::task::spawn_blocking(move || {
+            std::fs::read(path)
+                .ok()
+                .and_then(|contents| serde_json::from_slice::<BlockState>(&contents).ok())
+        })
+        .await
+        .ok()
+        .flatten();
+
+        // The block may have been loaded by another request, or removed, in the meantime
+        {
+            let mut blocks = self.blocks.lock().unwrap();
+            let Some(block) = blocks.get_mut(&key).filter(|block| block.id == id) else {
+                return;
+            };
+            if block.state.is_some() {
+                return;
+            }
+            match state {
+                Some(state) => {
+                    block.state = Some(state);
+                    return;
+                }
+                None => {
+                    log::warn!(target: "ts::fork_cache", "Dropping unreadable block {:?}", key);
+                    blocks.remove(&key);
+                }
+            }
+        }
+        self.remove_files(&[key]).await;
+    }
+
+    /// Evicts the least recently used blocks until the cache fits in its maximum size, apart from
+    /// the block being used. Returns the evicted blocks, whose files are left for the caller to
+    /// remove once the lock is released.
+    fn evict(
+        &self,
+        blocks: &mut HashMap<BlockKey, CachedBlock>,
+        keep: Option<BlockKey>,
+    ) -> Vec<BlockKey> {
+        let mut evicted = Vec::new();
+        let mut size: u64 = blocks.values().map(|block| block.size).sum();
+        while size > self.max_size {
+            let Some((oldest, block_size)) = blocks
+                .iter()
+                .filter(|(key, _)| Some(**key) != keep)
+                .min_by_key(|(_, block)| block.last_used)
+                .map(|(key, block)| (*key, block.size))
+            else {
+                break;
+            };
+            log::debug!(target: "ts::fork_cache", "Evicting block {:?}", oldest);
+            blocks.remove(&oldest);
+            evicted.push(oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            size -= block_size;
+        }
+        evicted
+    }
+
+    fn path(&self, key: &BlockKey) -> PathBuf {
+        self.dir
+            .join(key.chain_id.to_string())
+            .join(format!("{}.json", key.block_number))
+    }
+
+    fn paths(&self, keys: &[BlockKey]) -> Vec<PathBuf> {
+        keys.iter().map(|key| self.path(key)).collect()
+    }
+
+    /// Removes the files of blocks which are no longer cached, on a blocking thread
+    async fn remove_files(&self, keys: &[BlockKey]) {
+        if keys.is_empty() {
+            return;
+        }
+        let paths = self.paths(keys);
+        let _ = tokio::task::spawn_blocking(move || {
+            for path in paths {
+                remove_file(&path);
+            }
+        })
+        .await;
+    }
+}
+
+fn remove_file(path: &Path) {
+    // Blocks which were never flushed have no file
+    let _ = std::fs::remove_file(path);
+}
+
+/// Periodically writes new entries of the cache to disk.
+pub async fn flush_periodically(cache: Arc<ForkCache>) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = cache.flush().await {
+            log::error!(target: "ts::fork_cache", "Failed to write the fork cache: {err:#}");
+        }
+    }
+}
+
+/// Parses the chain id of a chain directory, or the block number of a block file
+fn parse_file_name(path: &Path, extension: Option<&str>) -> Option<u64> {
+    if path.extension().and_then(|extension| extension.to_str()) != extension {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Returns the block and the state a request asks for, if it is for state at a fixed block.
+/// Requests for a tag like `latest` can't be cached, as their answer changes over time.
+fn parse_request(request: &Value) -> Option<(u64, Entry)> {
+    let params = request["params"].as_array()?;
+    let param = |index: usize| params.get(index).cloned();
+    let address = || serde_json::from_value::<Address>(param(0)?).ok();
+
+    let (entry, block) = match request["method"].as_str()? {
+        "eth_getBalance" => (Entry::Balance(address()?), param(1)?),
+        "eth_getTransactionCount" => (Entry::Nonce(address()?), param(1)?),
+        "eth_getCode" => (Entry::Code(address()?), param(1)?),
+        "eth_getStorageAt" => {
+            let slot = serde_json::from_value::<Uint>(param(1)?).ok()?;
+            (Entry::Storage(address()?, slot), param(2)?)
+        }
+        _ => return None,
+    };
+    // Either a block number, or an EIP-1898 block object with one
+    let block = block.get("blockNumber").cloned().unwrap_or(block);
+    let block_number = serde_json::from_value::<U64>(block).ok()?;
+
+    Some((block_number.as_u64(), entry))
+}
+
+pub async fn fork_cache_stats(state: Arc<SharedSimulationState>) -> Result<Json, Rejection> {
+    let cache = state
+        .providers
+        .cache()
+        .ok_or_else(|| warp::reject::custom(ForkCacheDisabledError()))?;
+
+    Ok(warp::reply::json(&cache.stats()))
+}
+
+pub async fn fork_cache_purge(state: Arc<SharedSimulationState>) -> Result<Json, Rejection> {
+    purge(&state, None).await
+}
+
+pub async fn fork_cache_purge_chain(
+    chain_id: u64,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    purge(&state, Some(chain_id)).await
+}
+
+async fn purge(state: &SharedSimulationState, chain_id: Option<u64>) -> Result<Json, Rejection> {
+    let cache = state
+        .providers
+        .cache()
+        .ok_or_else(|| warp::reject::custom(ForkCacheDisabledError()))?;
+    let purged_blocks = cache.purge(chain_id).await;
+    log::info!(target: "ts::fork_cache", "Purged {} cached blocks", purged_blocks);
+
+    Ok(warp::reply::json(&ForkCachePurgeResponse { purged_blocks }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("temper-fork-cache-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn storage_request(slot: u64, block: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "eth_getStorageAt",
+            "params": ["0x000000000000000000000000000000000000c0de", format!("{slot:#x}"), block],
+        }))
+        .unwrap()
+    }
+
+    fn response(result: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": result }))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let address: Address = "0x000000000000000000000000000000000000c0de"
+            .parse()
+            .unwrap();
+        let request = |method: &str, params: Value| {
+            parse_request(&serde_json::json!({ "method": method, "params": params }))
+        };
+
+        assert_eq!(
+            request("eth_getBalance", serde_json::json!([address, "0x10"])),
+            Some((16, Entry::Balance(address)))
+        );
+        assert_eq!(
+            request(
+                "eth_getStorageAt",
+                serde_json::json!([address, "0x1", { "blockNumber": "0x10" }])
+            ),
+            Some((16, Entry::Storage(address, Uint::one())))
+        );
+        assert_eq!(
+            request("eth_getCode", serde_json::json!([address, "latest"])),
+            None
+        );
+        assert_eq!(
+            request("eth_call", serde_json::json!([{ "to": address }, "0x10"])),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fork_cache_persists() {
+        let dir = cache_dir("persists");
+        let cache = Arc::new(ForkCache::open(dir.clone(), u64::MAX).unwrap());
+
+        assert_eq!(cache.get(1, &storage_request(0, "0x10")).await, None);
+        cache
+            .insert(1, &storage_request(0, "0x10"), &response("0x2a"))
+            .await;
+        // Only the id of the request it answers changes
+        let cached: Value =
+            serde_json::from_slice(&cache.get(1, &storage_request(0, "0x10")).await.unwrap())
+                .unwrap();
+        assert_eq!(cached["id"], 7);
+        assert_eq!(cached["result"], "0x2a");
+        // Other blocks, chains and tags don't share the entry
+        assert_eq!(cache.get(1, &storage_request(0, "0x11")).await, None);
+        assert_eq!(cache.get(10, &storage_request(0, "0x10")).await, None);
+        assert_eq!(cache.get(1, &storage_request(0, "latest")).await, None);
+        cache.flush().await.unwrap();
+
+        let reopened = ForkCache::open(dir, u64::MAX).unwrap();
+        assert!(reopened.get(1, &storage_request(0, "0x10")).await.is_some());
+        let stats = reopened.stats();
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(stats.hits, 1);
+
+        assert_eq!(reopened.purge(Some(10)).await, 0);
+        assert_eq!(reopened.purge(Some(1)).await, 1);
+        assert_eq!(reopened.get(1, &storage_request(0, "0x10")).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_fork_cache_purged_blocks_stay_purged() {
+        let dir = cache_dir("purged");
+        let cache = Arc::new(ForkCache::open(dir.clone(), u64::MAX).unwrap());
+
+        cache
+            .insert(1, &storage_request(0, "0x10"), &response("0x2a"))
+            .await;
+        cache.flush().await.unwrap();
+        cache
+            .insert(1, &storage_request(1, "0x10"), &response("0x2b"))
+            .await;
+        assert_eq!(cache.purge(None).await, 1);
+        cache.flush().await.unwrap();
+
+        assert_eq!(ForkCache::open(dir, u64::MAX).unwrap().stats().blocks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_fork_cache_evicts_least_recently_used() {
+        let cache = ForkCache::open(cache_dir("evicts"), 200).unwrap();
+
+        cache
+            .insert(1, &storage_request(0, "0x10"), &response("0x1"))
+            .await;
+        cache
+            .insert(1, &storage_request(0, "0x11"), &response("0x1"))
+            .await;
+        // Using the first block makes the second the least recently used one
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(cache.get(1, &storage_request(0, "0x10")).await.is_some());
+        cache
+            .insert(1, &storage_request(0, "0x12"), &response("0x1"))
+            .await;
+
+        assert!(cache.get(1, &storage_request(0, "0x10")).await.is_some());
+        assert_eq!(cache.get(1, &storage_request(0, "0x11")).await, None);
+        assert!(cache.get(1, &storage_request(0, "0x12")).await.is_some());
+        assert_eq!(cache.stats().evictions, 1);
+    }
+}
@@ -1,55 +1,195 @@
 use dashmap::DashMap;
-use evm::Evm;
+use ethers::abi::{Address, Hash};
+use fork::ForkPool;
+use provider::ProviderPool;
+use replay::{ReplayBlockRequest, ReplayRequest};
 use serde::de::DeserializeOwned;
-use simulation::{SimulationRequest, StatefulSimulationRequest};
+use session::{Session, SessionSlots};
+use simulation::{
+    RawSimulationRequest, SimulateV1Request, SimulationRequest, StatefulSimulationRequest,
+};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 
+pub mod assets;
+pub mod chains;
 pub mod config;
 use config::Config;
 
 pub mod errors;
 pub mod evm;
+pub mod fees;
+pub mod fork;
+pub mod fork_cache;
+pub mod provider;
+pub mod replay;
+pub mod revert;
+pub mod rpc;
+pub mod session;
 
 pub mod simulation;
+pub mod state_diff;
+pub mod state_dump;
+pub mod tracer;
 
 pub struct SharedSimulationState {
-    pub evms: Arc<DashMap<Uuid, Arc<Mutex<Evm>>>>,
+    pub evms: Arc<DashMap<Uuid, Arc<Session>>>,
+    pub forks: Arc<ForkPool>,
+    pub providers: Arc<ProviderPool>,
+    pub session_slots: SessionSlots,
 }
 
 pub fn simulate_routes(
     config: Config,
     state: Arc<SharedSimulationState>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    simulate(config.clone())
-        .or(simulate_bundle(config.clone()))
+    simulate(config.clone(), state.clone())
+        .or(simulate_bundle(config.clone(), state.clone()))
+        .or(simulate_raw(config.clone(), state.clone()))
+        .or(simulate_v1(config.clone(), state.clone()))
+        .or(replay_transaction(config.clone(), state.clone()))
+        .or(replay_block(config.clone(), state.clone()))
+        .or(estimate_gas(config.clone(), state.clone()))
+        .or(estimate_gas_bundle(config.clone(), state.clone()))
+        .or(create_access_list(config.clone(), state.clone()))
         .or(simulate_stateful_new(config.clone(), state.clone()))
-        .or(simulate_stateful(config, state.clone()))
+        .or(simulate_stateful(config.clone(), state.clone()))
         .or(simulate_stateful_end(state.clone()))
+        .or(simulate_stateful_snapshot(state.clone()))
+        .or(simulate_stateful_revert(state.clone()))
+        .or(simulate_stateful_list(state.clone()))
+        .or(simulate_stateful_info(state.clone()))
+        .or(simulate_stateful_balance(state.clone()))
+        .or(simulate_stateful_nonce(state.clone()))
+        .or(simulate_stateful_code(state.clone()))
+        .or(simulate_stateful_storage(state.clone()))
+        .or(simulate_stateful_state(state.clone()))
+        .or(simulate_stateful_call(config.clone(), state.clone()))
+        .or(simulate_stateful_rpc(config, state.clone()))
+        .or(rpc_endpoints(state.clone()))
+        .or(fork_cache_stats(state.clone()))
+        .or(fork_cache_purge(state.clone()))
+        .or(fork_cache_purge_chain(state))
 }
 
 /// POST /simulate
-pub fn simulate(config: Config) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn simulate(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("simulate")
         .and(warp::post())
         .and(json_body::<SimulationRequest>(&config))
         .and(with_config(config))
+        .and(with_state(state))
         .and_then(simulation::simulate)
 }
 
 /// POST /simulate-bundle
 pub fn simulate_bundle(
     config: Config,
+    state: Arc<SharedSimulationState>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("simulate-bundle")
         .and(warp::post())
         .and(json_body(&config))
         .and(with_config(config))
+        .and(with_state(state))
         .and_then(simulation::simulate_bundle)
 }
 
+/// POST /simulate-raw
+pub fn simulate_raw(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-raw")
+        .and(warp::post())
+        .and(json_body::<RawSimulationRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(simulation::simulate_raw)
+}
+
+/// POST /eth-simulate-v1
+pub fn simulate_v1(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("eth-simulate-v1")
+        .and(warp::post())
+        .and(json_body::<SimulateV1Request>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(simulation::simulate_v1)
+}
+
+/// POST /replay/{transactionHash}
+pub fn replay_transaction(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("replay" / Hash)
+        .and(warp::post())
+        .and(json_body::<ReplayRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(replay::replay_transaction)
+}
+
+/// POST /replay-block/{blockNumber}
+pub fn replay_block(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("replay-block" / u64)
+        .and(warp::post())
+        .and(json_body::<ReplayBlockRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(replay::replay_block)
+}
+
+/// POST /estimate-gas
+pub fn estimate_gas(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("estimate-gas")
+        .and(warp::post())
+        .and(json_body::<SimulationRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(simulation::estimate_gas)
+}
+
+/// POST /estimate-gas-bundle
+pub fn estimate_gas_bundle(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("estimate-gas-bundle")
+        .and(warp::post())
+        .and(json_body(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(simulation::estimate_gas_bundle)
+}
+
+/// POST /create-access-list
+pub fn create_access_list(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("create-access-list")
+        .and(warp::post())
+        .and(json_body::<SimulationRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(simulation::create_access_list)
+}
+
 /// POST /simulate-stateful
 pub fn simulate_stateful_new(
     config: Config,
@@ -85,6 +225,161 @@ pub fn simulate_stateful(
         .and_then(simulation::simulate_stateful)
 }
 
+/// POST /simulate-stateful/{statefulSimulationId}/snapshot
+pub fn simulate_stateful_snapshot(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "snapshot")
+        .and(warp::post())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_snapshot)
+}
+
+/// POST /simulate-stateful/{statefulSimulationId}/revert/{snapshotId}
+pub fn simulate_stateful_revert(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "revert" / u64)
+        .and(warp::post())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_revert)
+}
+
+/// GET /simulate-stateful
+pub fn simulate_stateful_list(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_list)
+}
+
+/// GET /simulate-stateful/{statefulSimulationId}
+pub fn simulate_stateful_info(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid)
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_info)
+}
+
+/// GET /simulate-stateful/{statefulSimulationId}/balance/{address}
+pub fn simulate_stateful_balance(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "balance" / Address)
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_balance)
+}
+
+/// GET /simulate-stateful/{statefulSimulationId}/nonce/{address}
+pub fn simulate_stateful_nonce(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "nonce" / Address)
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_nonce)
+}
+
+/// GET /simulate-stateful/{statefulSimulationId}/code/{address}
+pub fn simulate_stateful_code(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "code" / Address)
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_code)
+}
+
+/// GET /simulate-stateful/{statefulSimulationId}/storage/{address}/{slot}
+pub fn simulate_stateful_storage(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "storage" / Address / Hash)
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_storage)
+}
+
+/// GET /simulate-stateful/{statefulSimulationId}/state
+pub fn simulate_stateful_state(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "state")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_state)
+}
+
+/// POST /simulate-stateful/{statefulSimulationId}/call
+pub fn simulate_stateful_call(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "call")
+        .and(warp::post())
+        .and(json_body::<SimulationRequest>(&config))
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_call)
+}
+
+/// POST /simulate-stateful/{statefulSimulationId}/rpc
+pub fn simulate_stateful_rpc(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "rpc")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(config.max_request_size))
+        .and(warp::body::bytes())
+        .and(with_state(state))
+        .and_then(rpc::simulate_stateful_rpc)
+}
+
+/// GET /rpc-endpoints
+pub fn rpc_endpoints(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("rpc-endpoints")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(provider::endpoint_stats)
+}
+
+/// GET /fork-cache
+pub fn fork_cache_stats(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("fork-cache")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(fork_cache::fork_cache_stats)
+}
+
+/// DELETE /fork-cache
+pub fn fork_cache_purge(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("fork-cache")
+        .and(warp::delete())
+        .and(with_state(state))
+        .and_then(fork_cache::fork_cache_purge)
+}
+
+/// DELETE /fork-cache/{chainId}
+pub fn fork_cache_purge_chain(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("fork-cache" / u64)
+        .and(warp::delete())
+        .and(with_state(state))
+        .and_then(fork_cache::fork_cache_purge_chain)
+}
+
 fn with_config(
     config: Config,
 ) -> impl Filter<Extract = (Config,), Error = std::convert::Infallible> + Clone {
@@ -1,10 +1,13 @@
 use std::{env, sync::Arc, path::Path};
 use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio_stream::wrappers::UnixListenerStream;
 use warp::Filter;
 use dashmap::DashMap;
 use enso_temper::{
-    config::config, errors::handle_rejection, simulate_routes, SharedSimulationState,
+    config::config, errors::handle_rejection, fork::ForkPool,
+    fork_cache::{flush_periodically, ForkCache}, provider::ProviderPool,
+    session::{reap_idle_sessions, SessionSlots}, simulate_routes, SharedSimulationState,
 };
 
 #[tokio::main]
@@ -30,11 +33,31 @@ async fn main() {
         api_base.boxed()
     };
 
+    // Cache of the state fetched by forks, kept on disk across restarts
+    let fork_cache = config.fork_cache_dir.clone().map(|dir| {
+        let cache = ForkCache::open(dir, config.fork_cache_size)
+            .expect("FORK_CACHE_DIR must be a writable directory");
+        Arc::new(cache)
+    });
+    if let Some(fork_cache) = &fork_cache {
+        tokio::spawn(flush_periodically(fork_cache.clone()));
+    }
+
     // Shared state for both the HTTP and UDS servers
     let shared_state = Arc::new(SharedSimulationState {
         evms: Arc::new(DashMap::new()),
+        forks: Arc::new(
+            ForkPool::new(config.fork_pool_size).with_rpc_cache(fork_cache.is_none()),
+        ),
+        providers: Arc::new(ProviderPool::new(fork_cache)),
+        session_slots: SessionSlots::default(),
     });
 
+    // Reap stateful simulations which clients never ended
+    if let Some(session_ttl) = config.session_ttl {
+        tokio::spawn(reap_idle_sessions(shared_state.evms.clone(), session_ttl));
+    }
+
     // Define Warp routes
     let routes = api_base
         .and(simulate_routes(config.clone(), shared_state.clone()))
@@ -76,5 +99,24 @@ async fn main() {
         _ = uds_server => {
             log::info!(target: "ts::api", "UDS server has stopped");
         },
+        _ = shutdown_signal() => {
+            log::info!(target: "ts::api", "Shutting down");
+        },
+    }
+
+    // Write what forks fetched since the last periodic flush
+    if let Some(fork_cache) = shared_state.providers.cache() {
+        if let Err(err) = fork_cache.flush().await {
+            log::error!(target: "ts::fork_cache", "Failed to write the fork cache: {err:#}");
+        }
+    }
+}
+
+/// Resolves on ctrl-c, or on SIGTERM which is how containers are stopped
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
     }
 }
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use eyre::eyre;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::http::StatusCode;
+use warp::reply::Json;
+use warp::{Filter, Rejection, Reply};
+
+use crate::chains::ChainConfig;
+use crate::errors::RpcError;
+use crate::fork_cache::ForkCache;
+use crate::SharedSimulationState;
+
+/// How many times every endpoint of a chain is tried before a request fails
+const MAX_ATTEMPTS: u32 = 3;
+/// Doubled after every round of attempts
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Consecutive failures after which an endpoint is skipped while others are healthy
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long an unhealthy endpoint is skipped for, before it is given another chance
+const COOLDOWN: Duration = Duration::from_secs(30);
+
+/// JSON-RPC error codes which mean the endpoint is rate limiting us, rather than that the request
+/// itself is wrong
+const RATE_LIMITED_CODES: [i64; 2] = [429, -32005];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointStats {
+    pub chain_id: u64,
+    /// Only the origin of the endpoint, as the rest of the URL often holds an API key
+    pub url: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub average_latency_ms: u64,
+    pub consecutive_failures: u32,
+    pub healthy: bool,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    requests: u64,
+    errors: u64,
+    total_latency: Duration,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Health {
+    fn is_available(&self, now: Instant) -> bool {
+        self.open_until.map_or(true, |until| now >= until)
+    }
+
+    fn record_success(&mut self, latency: Duration) {
+        self.requests += 1;
+        self.total_latency += latency;
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    /// Records a failure, and returns whether the circuit was opened by it
+    fn record_failure(&mut self, latency: Duration, now: Instant) -> bool {
+        self.requests += 1;
+        self.errors += 1;
+        self.total_latency += latency;
+        self.consecutive_failures += 1;
+        // An endpoint which fails again after its cooldown is skipped for another one right away
+        let open = self.consecutive_failures >= FAILURE_THRESHOLD;
+        if open {
+            self.open_until = Some(now + COOLDOWN);
+        }
+        open
+    }
+
+    fn average_latency(&self) -> Duration {
+        match u32::try_from(self.requests) {
+            Ok(requests) if requests > 0 => self.total_latency / requests,
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+struct Endpoint {
+    url: Url,
+    health: Mutex<Health>,
+}
+
+impl Endpoint {
+    fn origin(&self) -> String {
+        self.url.origin().ascii_serialization()
+    }
+}
+
+/// Sends JSON-RPC requests to the endpoints of a chain in order of preference, moving on to the
+/// next endpoint when one fails and backing off between rounds. Endpoints which keep failing are
+/// skipped until their cooldown is over, unless no other endpoint is left.
+pub struct ChainProvider {
+    chain_id: u64,
+    endpoints: Vec<Endpoint>,
+    client: Client,
+}
+
+impl ChainProvider {
+    pub fn new(chain_id: u64, chain: &ChainConfig) -> eyre::Result<Self> {
+        let endpoints = chain
+            .rpc_urls
+            .iter()
+            .map(|url| {
+                Url::parse(url).map(|url| Endpoint {
+                    url,
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let client = Client::builder()
+            .default_headers(chain.header_map())
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(ChainProvider {
+            chain_id,
+            endpoints,
+            client,
+        })
+    }
+
+    /// Sends a JSON-RPC request body, and returns the response body of the first endpoint which
+    /// answered it
+    pub async fn request(&self, body: Bytes) -> eyre::Result<Bytes> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut last_error = eyre!("no RPC endpoints configured");
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            for endpoint in self.candidates() {
+                let started = Instant::now();
+                let result = self.send(endpoint, body.clone()).await;
+                let latency = started.elapsed();
+
+                match result {
+                    Ok(response) => {
+                        endpoint.health.lock().unwrap().record_success(latency);
+                        return Ok(response);
+                    }
+                    Err(err) => {
+                        let opened = endpoint
+                            .health
+                            .lock()
+                            .unwrap()
+                            .record_failure(latency, Instant::now());
+                        log::warn!(
+                            target: "ts::provider",
+                            "Request to {} on chain {} failed: {err:#}",
+                            endpoint.origin(),
+                            self.chain_id,
+                        );
+                        if opened {
+                            log::warn!(
+                                target: "ts::provider",
+                                "Skipping {} on chain {} for {}s",
+                                endpoint.origin(),
+                                self.chain_id,
+                                COOLDOWN.as_secs(),
+                            );
+                        }
+                        last_error = err;
+                    }
+                }
+            }
+
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+
+        Err(last_error.wrap_err(format!(
+            "every RPC endpoint of chain {} failed",
+            self.chain_id
+        )))
+    }
+
+    pub fn stats(&self) -> Vec<EndpointStats> {
+        let now = Instant::now();
+        self.endpoints
+            .iter()
+            .map(|endpoint| {
+                let health = endpoint.health.lock().unwrap();
+                EndpointStats {
+                    chain_id: self.chain_id,
+                    url: endpoint.origin(),
+                    requests: health.requests,
+                    errors: health.errors,
+                    average_latency_ms: health.average_latency().as_millis() as u64,
+                    consecutive_failures: health.consecutive_failures,
+                    healthy: health.is_available(now),
+                }
+            })
+            .collect()
+    }
+
+    /// The endpoints to try in this round: the healthy ones, or all of them if none are healthy
+    fn candidates(&self) -> Vec<&Endpoint> {
+        let now = Instant::now();
+        let available: Vec<&Endpoint> = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.health.lock().unwrap().is_available(now))
+            .collect();
+        if available.is_empty() {
+            self.endpoints.iter().collect()
+        } else {
+            available
+        }
+    }
+
+    async fn send(&self, endpoint: &Endpoint, body: Bytes) -> eyre::Result<Bytes> {
+        let response = self
+            .client
+            .post(endpoint.url.clone())
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        let parsed: Value = serde_json::from_slice(&response)?;
+        let responses = match &parsed {
+            Value::Array(responses) => responses.iter().collect(),
+            response => vec![response],
+        };
+        for response in responses {
+            if let Some(code) = response["error"]["code"].as_i64() {
+                if RATE_LIMITED_CODES.contains(&code) {
+                    return Err(eyre!("rate limited: {}", response["error"]["message"]));
+                }
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+struct Relay {
+    provider: Arc<ChainProvider>,
+    url: String,
+}
+
+/// The providers of every chain in use, each behind a relay: a JSON-RPC server on localhost which
+/// forwards to the provider.
+///
+/// Forks are pointed at the relay of their chain instead of an endpoint, so that the failover
+/// covers everything the forked backend fetches as well. With a fork cache, the relays answer
+/// the state requests they have seen before from the cache.
+pub struct ProviderPool {
+    relays: Mutex<HashMap<u64, Relay>>,
+    cache: Option<Arc<ForkCache>>,
+}
+
+impl ProviderPool {
+    pub fn new(cache: Option<Arc<ForkCache>>) -> Self {
+        ProviderPool {
+            relays: Mutex::new(HashMap::new()),
+            cache,
+        }
+    }
+
+    pub fn cache(&self) -> Option<&Arc<ForkCache>> {
+        self.cache.as_ref()
+    }
+
+    /// Returns the URL of the relay of the chain, starting it if it isn't running yet
+    pub fn relay_url(&self, chain_id: u64, chain: &ChainConfig) -> Result<String, RpcError> {
+        let mut relays = self.relays.lock().unwrap();
+        if let Some(relay) = relays.get(&chain_id) {
+            return Ok(relay.url.clone());
+        }
+
+        let provider = Arc::new(ChainProvider::new(chain_id, chain).map_err(RpcError)?);
+        let url = spawn_relay(provider.clone(), self.cache.clone())?;
+        log::debug!(target: "ts::provider", "Relaying chain {chain_id} through {url}");
+        relays.insert(
+            chain_id,
+            Relay {
+                provider,
+                url: url.clone(),
+            },
+        );
+
+        Ok(url)
+    }
+
+    pub fn stats(&self) -> Vec<EndpointStats> {
+        let relays = self.relays.lock().unwrap();
+        let mut stats: Vec<EndpointStats> = relays
+            .values()
+            .flat_map(|relay| relay.provider.stats())
+            .collect();
+        // Endpoints of a chain keep their order of preference
+        stats.sort_by_key(|endpoint| endpoint.chain_id);
+        stats
+    }
+}
+
+fn spawn_relay(
+    provider: Arc<ChainProvider>,
+    cache: Option<Arc<ForkCache>>,
+) -> Result<String, RpcError> {
+    let relay = warp::post()
+        .and(warp::body::bytes())
+        .and_then(move |body: Bytes| {
+            let provider = provider.clone();
+            let cache = cache.clone();
+            async move { Ok::<_, Infallible>(relay(&provider, cache.as_deref(), body).await) }
+        });
+    let (address, server) = warp::serve(relay)
+        .try_bind_ephemeral(([127, 0, 0, 1], 0))
+        .map_err(|err| RpcError(err.into()))?;
+    tokio::spawn(server);
+
+    Ok(format!("http://{address}"))
+}
+
+async fn relay(
+    provider: &ChainProvider,
+    cache: Option<&ForkCache>,
+    body: Bytes,
+) -> warp::reply::Response {
+    if let Some(cache) = cache {
+        if let Some(response) = cache.get(provider.chain_id, &body).await {
+            return json_response(response);
+        }
+    }
+
+    match provider.request(body.clone()).await {
+        Ok(response) => {
+            if let Some(cache) = cache {
+                cache.insert(provider.chain_id, &body, &response).await;
+            }
+            json_response(response.to_vec())
+        }
+        Err(err) => {
+            let id = serde_json::from_slice::<Value>(&body)
+                .map(|request| request["id"].clone())
+                .unwrap_or_default();
+            let error = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32603, "message": format!("{err:#}") },
+            });
+            warp::reply::with_status(warp::reply::json(&error), StatusCode::BAD_GATEWAY)
+                .into_response()
+        }
+    }
+}
+
+fn json_response(body: Vec<u8>) -> warp::reply::Response {
+    warp::reply::with_header(body, "Content-Type", "application/json").into_response()
+}
+
+pub async fn endpoint_stats(state: Arc<SharedSimulationState>) -> Result<Json, Rejection> {
+    Ok(warp::reply::json(&state.providers.stats()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_circuit() {
+        let mut health = Health::default();
+        let now = Instant::now();
+        let latency = Duration::from_millis(10);
+
+        for _ in 1..FAILURE_THRESHOLD {
+            assert!(!health.record_failure(latency, now));
+            assert!(health.is_available(now));
+        }
+        assert!(health.record_failure(latency, now));
+        assert!(!health.is_available(now));
+        assert!(health.is_available(now + COOLDOWN));
+
+        // After the cooldown a single failure opens the circuit again
+        assert!(health.record_failure(latency, now + COOLDOWN));
+        assert!(!health.is_available(now + COOLDOWN));
+
+        health.record_success(latency);
+        assert!(health.is_available(now));
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.requests, u64::from(FAILURE_THRESHOLD) + 2);
+        assert_eq!(health.errors, u64::from(FAILURE_THRESHOLD) + 1);
+        assert_eq!(health.average_latency(), latency);
+    }
+}
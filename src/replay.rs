@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ethers::abi::{Address, Hash, Uint};
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::{Block, Bytes, Transaction, TransactionReceipt, U64};
+use serde::{Deserialize, Serialize};
+use warp::reply::Json;
+use warp::Rejection;
+
+use crate::chains::ChainConfig;
+use crate::config::Config;
+use crate::errors::{
+    BlockNotFoundError, IncorrectChainIdError, InvalidBlockNumbersError, LocalChainError, RpcError,
+    TransactionNotFoundError,
+};
+use crate::evm::{CallRawRequest, Evm};
+use crate::simulation::{
+    chain_config, fork, run, signed_transaction_request, SimulationResponse, StateOverride,
+};
+use crate::tracer::TraceConfig;
+use crate::SharedSimulationState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayRequest {
+    pub chain_id: u64,
+    /// Replaces the calldata of the transaction
+    pub data: Option<Bytes>,
+    pub state_overrides: Option<HashMap<Address, StateOverride>>,
+    pub format_trace: Option<bool>,
+    pub include_state_diff: Option<bool>,
+    pub trace_config: Option<TraceConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayBlockRequest {
+    pub chain_id: u64,
+    pub format_trace: Option<bool>,
+    pub include_state_diff: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayBlockResponse {
+    pub block_number: u64,
+    /// Whether every transaction used the same gas and ended with the same status as on chain
+    pub matches: bool,
+    pub transactions: Vec<ReplayedTransaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayedTransaction {
+    pub transaction_hash: Hash,
+    pub receipt_gas_used: Option<Uint>,
+    /// Missing for transactions from before Byzantium, whose receipts have no status
+    pub receipt_success: Option<bool>,
+    pub matches: bool,
+    #[serde(flatten)]
+    pub simulation: SimulationResponse,
+}
+
+/// Replays a mined transaction on top of the state it was executed against: the parent block
+/// plus every transaction that came before it in its block.
+pub async fn replay_transaction(
+    hash: Hash,
+    request: ReplayRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let chain = chain_config(&config, request.chain_id)?;
+    let provider = provider(&state, request.chain_id, &chain)?;
+
+    let transaction = provider
+        .get_transaction(hash)
+        .await
+        .map_err(|err| warp::reject::custom(RpcError(err.into())))?
+        .ok_or_else(|| warp::reject::custom(TransactionNotFoundError()))?;
+    // Pending transactions haven't been executed yet, so there is nothing to replay
+    let block_number = transaction
+        .block_number
+        .ok_or_else(|| warp::reject::custom(TransactionNotFoundError()))?;
+    let block = fetch_block(&provider, block_number).await?;
+
+    let mut evm = fork_parent(&state, request.chain_id, &chain, &block).await?;
+
+    for preceding in block.transactions.iter().take_while(|tx| tx.hash != hash) {
+        evm.call_raw_committing(
+            CallRawRequest::from(preceding.clone()),
+            preceding.gas.low_u64(),
+        )
+        .await?;
+    }
+
+    let mut transaction = signed_transaction_request(request.chain_id, transaction)?;
+    if let Some(data) = request.data {
+        transaction.data = Some(data);
+    }
+    transaction.state_overrides = request.state_overrides;
+    transaction.format_trace = request.format_trace;
+    transaction.include_state_diff = request.include_state_diff;
+    transaction.trace_config = request.trace_config;
+
+    let response = run(&mut evm, transaction, true).await?;
+
+    Ok(warp::reply::json(&response))
+}
+
+/// Re-executes every transaction of a block on top of its parent, and compares the gas used and
+/// status of each with its receipt.
+pub async fn replay_block(
+    number: u64,
+    request: ReplayBlockRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let chain = chain_config(&config, request.chain_id)?;
+    let provider = provider(&state, request.chain_id, &chain)?;
+
+    let block = fetch_block(&provider, U64::from(number)).await?;
+    let receipts = block_receipts(&provider, &block).await?;
+
+    let mut evm = fork_parent(&state, request.chain_id, &chain, &block).await?;
+
+    let mut transactions = Vec::with_capacity(block.transactions.len());
+    for (transaction, receipt) in block.transactions.iter().zip(receipts) {
+        let transaction_hash = transaction.hash;
+        let mut transaction = signed_transaction_request(request.chain_id, transaction.clone())?;
+        transaction.format_trace = request.format_trace;
+        transaction.include_state_diff = request.include_state_diff;
+
+        let simulation = run(&mut evm, transaction, true).await?;
+        let receipt_success = receipt.status.map(|status| status == U64::one());
+        let matches = receipt.gas_used == Some(Uint::from(simulation.gas_used))
+            && receipt_success.map_or(true, |success| success == simulation.success);
+
+        transactions.push(ReplayedTransaction {
+            transaction_hash,
+            receipt_gas_used: receipt.gas_used,
+            receipt_success,
+            matches,
+            simulation,
+        });
+    }
+
+    Ok(warp::reply::json(&ReplayBlockResponse {
+        block_number: number,
+        matches: transactions.iter().all(|transaction| transaction.matches),
+        transactions,
+    }))
+}
+
+async fn fetch_block(
+    provider: &Provider<Http>,
+    number: U64,
+) -> Result<Block<Transaction>, Rejection> {
+    provider
+        .get_block_with_txs(number)
+        .await
+        .map_err(|err| warp::reject::custom(RpcError(err.into())))?
+        .ok_or_else(|| warp::reject::custom(BlockNotFoundError()))
+}
+
+/// Fetches the receipts of a block in one request if the node supports `eth_getBlockReceipts`,
+/// and one by one otherwise.
+async fn block_receipts(
+    provider: &Provider<Http>,
+    block: &Block<Transaction>,
+) -> Result<Vec<TransactionReceipt>, Rejection> {
+    if let Some(number) = block.number {
+        if let Ok(receipts) = provider.get_block_receipts(number).await {
+            if receipts.len() == block.transactions.len() {
+                return Ok(receipts);
+            }
+        }
+    }
+
+    let mut receipts = Vec::with_capacity(block.transactions.len());
+    for transaction in &block.transactions {
+        let receipt = provider
+            .get_transaction_receipt(transaction.hash)
+            .await
+            .map_err(|err| warp::reject::custom(RpcError(err.into())))?
+            .ok_or_else(|| warp::reject::custom(TransactionNotFoundError()))?;
+        receipts.push(receipt);
+    }
+    Ok(receipts)
+}
+
+/// A provider for the relay of the chain, which fails over between the chain's RPC endpoints.
+/// Local chains have no history to replay.
+fn provider(
+    state: &SharedSimulationState,
+    chain_id: u64,
+    chain: &ChainConfig,
+) -> Result<Provider<Http>, Rejection> {
+    if chain.local.is_some() {
+        return Err(warp::reject::custom(LocalChainError()));
+    }
+    let relay_url = state.providers.relay_url(chain_id, chain)?;
+    Provider::<Http>::try_from(relay_url).map_err(|err| warp::reject::custom(RpcError(err.into())))
+}
+
+/// Forks at the parent of the given block, with the environment of the block itself so that its
+/// transactions execute as they did when it was mined.
+async fn fork_parent(
+    state: &SharedSimulationState,
+    chain_id: u64,
+    chain: &ChainConfig,
+    block: &Block<Transaction>,
+) -> Result<Evm, Rejection> {
+    let number = block
+        .number
+        .filter(|number| !number.is_zero())
+        .ok_or_else(|| warp::reject::custom(InvalidBlockNumbersError()))?
+        .as_u64();
+    let gas_limit = block.gas_limit.low_u64();
+
+    let fork = fork(state, chain_id, chain, Some(number - 1))?;
+    let mut evm = Evm::new(None, fork, gas_limit, true, chain);
+
+    if evm.get_chain_id() != Uint::from(chain_id) {
+        return Err(warp::reject::custom(IncorrectChainIdError()));
+    }
+
+    evm.set_block(number).await?;
+    evm.set_block_timestamp(block.timestamp.as_u64()).await?;
+    evm.set_block_gas_limit(gas_limit);
+    if let Some(author) = block.author {
+        evm.set_coinbase(author);
+    }
+    if let Some(base_fee) = block.base_fee_per_gas {
+        evm.set_base_fee(base_fee);
+    }
+    // After the merge the mix hash is the randomness, and the difficulty is zero
+    if let Some(mix_hash) = block.mix_hash {
+        evm.set_prevrandao(mix_hash);
+    }
+    evm.set_difficulty(block.difficulty);
+
+    Ok(evm)
+}
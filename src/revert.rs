@@ -0,0 +1,161 @@
+use ethers::abi::{decode, AbiError, Function, ParamType, Token, Uint};
+use ethers::types::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// `bytes4(keccak256("Error(string)"))`
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+/// `bytes4(keccak256("Panic(uint256)"))`
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RevertReason {
+    /// `revert("message")` or `require(condition, "message")`
+    Error { message: String },
+    /// A failed `assert`, arithmetic error or other compiler inserted check
+    Panic { code: Uint, reason: String },
+    /// A custom Solidity error. `signature` and `args` are only set if it could be identified.
+    Custom {
+        selector: Bytes,
+        signature: Option<String>,
+        args: Vec<String>,
+    },
+}
+
+impl RevertReason {
+    /// Decodes `Error(string)` and `Panic(uint256)` reverts. Anything else is returned as an
+    /// unidentified custom error, or `None` if the data is too short to hold a selector.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 {
+            return None;
+        }
+        let (selector, args) = data.split_at(4);
+
+        if selector == ERROR_SELECTOR {
+            if let Ok(mut tokens) = decode(&[ParamType::String], args) {
+                if let Some(Token::String(message)) = tokens.pop() {
+                    return Some(RevertReason::Error { message });
+                }
+            }
+        } else if selector == PANIC_SELECTOR {
+            if let Ok(mut tokens) = decode(&[ParamType::Uint(256)], args) {
+                if let Some(Token::Uint(code)) = tokens.pop() {
+                    return Some(RevertReason::Panic {
+                        code,
+                        reason: panic_reason(code).to_string(),
+                    });
+                }
+            }
+        }
+
+        Some(RevertReason::Custom {
+            selector: Bytes::from(selector.to_vec()),
+            signature: None,
+            args: vec![],
+        })
+    }
+
+    /// Decodes a custom error with the ABI of an error that has a matching selector.
+    pub fn from_error(error: &AbiError, data: &[u8]) -> Option<Self> {
+        let inputs: Vec<ParamType> = error
+            .inputs
+            .iter()
+            .map(|input| input.kind.clone())
+            .collect();
+        let tokens = decode(&inputs, data.get(4..)?).ok()?;
+
+        Some(RevertReason::Custom {
+            selector: Bytes::from(data[..4].to_vec()),
+            signature: Some(format!(
+                "{}({})",
+                error.name,
+                inputs
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )),
+            args: tokens.iter().map(ToString::to_string).collect(),
+        })
+    }
+
+    /// Decodes a custom error with a signature found by a function signature lookup, as custom
+    /// errors share their selector format with functions.
+    pub fn from_function(function: &Function, data: &[u8]) -> Option<Self> {
+        let tokens = function.decode_input(data.get(4..)?).ok()?;
+
+        Some(RevertReason::Custom {
+            selector: Bytes::from(data[..4].to_vec()),
+            signature: Some(function.signature()),
+            args: tokens.iter().map(ToString::to_string).collect(),
+        })
+    }
+}
+
+/// Describes the panic codes inserted by the Solidity compiler, see
+/// https://docs.soliditylang.org/en/latest/control-structures.html#panic-via-assert-and-error-via-require
+pub fn panic_reason(code: Uint) -> &'static str {
+    if code > Uint::from(u8::MAX) {
+        return "unknown panic code";
+    }
+    match code.as_u32() {
+        0x00 => "generic compiler panic",
+        0x01 => "assertion failed",
+        0x11 => "arithmetic underflow or overflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "invalid enum value",
+        0x22 => "invalid storage byte array encoding",
+        0x31 => "pop on empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "out of memory",
+        0x51 => "call to uninitialized function",
+        _ => "unknown panic code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::encode;
+
+    fn with_selector(selector: [u8; 4], tokens: &[Token]) -> Vec<u8> {
+        [selector.to_vec(), encode(tokens)].concat()
+    }
+
+    #[test]
+    fn test_decode_error_string() {
+        let data = with_selector(ERROR_SELECTOR, &[Token::String("not enough".to_string())]);
+        assert_eq!(
+            RevertReason::decode(&data),
+            Some(RevertReason::Error {
+                message: "not enough".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_panic() {
+        let data = with_selector(PANIC_SELECTOR, &[Token::Uint(Uint::from(0x11))]);
+        assert_eq!(
+            RevertReason::decode(&data),
+            Some(RevertReason::Panic {
+                code: Uint::from(0x11),
+                reason: "arithmetic underflow or overflow".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_custom_error() {
+        let data = with_selector([0xde, 0xad, 0xbe, 0xef], &[Token::Uint(Uint::one())]);
+        assert_eq!(
+            RevertReason::decode(&data),
+            Some(RevertReason::Custom {
+                selector: Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]),
+                signature: None,
+                args: vec![],
+            })
+        );
+        assert_eq!(RevertReason::decode(&[]), None);
+    }
+}
@@ -0,0 +1,415 @@
+use std::sync::Arc;
+
+use ethers::abi::{Address, Hash, Uint};
+use ethers::types::transaction::eip2930::AccessList;
+use ethers::types::{
+    Bytes, Log, Signature, Transaction, TransactionReceipt, TransactionRequest, U64,
+};
+use ethers::utils::keccak256;
+use revm::interpreter::InstructionResult;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+use warp::hyper::body::Bytes as Body;
+use warp::reply::Json;
+use warp::Rejection;
+
+use crate::errors::EvmError;
+use crate::evm::{CallRawRequest, CallRawResult, CommittedTransaction, Evm};
+use crate::fees::effective_gas_price;
+use crate::revert::RevertReason;
+use crate::simulation::{decode_raw_transaction, get_session};
+use crate::SharedSimulationState;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const SERVER_ERROR: i64 = -32000;
+/// Used by geth for reverted calls, with the revert data as the error's `data`
+pub const EXECUTION_REVERTED: i64 = 3;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonRpcRequest {
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
+impl JsonRpcResponse {
+    fn new(id: Value, result: Result<Value, JsonRpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        JsonRpcResponse {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl JsonRpcError {
+    pub(crate) fn new(code: i64, message: impl Into<String>) -> Self {
+        JsonRpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+/// The transaction object taken by `eth_call`, `eth_estimateGas`, `eth_sendTransaction` and
+/// `eth_simulateV1`. Fee fields and the nonce are accepted but ignored.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallRequest {
+    pub from: Option<Address>,
+    pub to: Option<Address>,
+    pub gas: Option<U64>,
+    pub value: Option<Uint>,
+    pub data: Option<Bytes>,
+    pub input: Option<Bytes>,
+    pub access_list: Option<AccessList>,
+}
+
+impl From<CallRequest> for CallRawRequest {
+    fn from(request: CallRequest) -> Self {
+        CallRawRequest {
+            from: request.from.unwrap_or_default(),
+            to: request.to,
+            value: request.value,
+            data: request.input.or(request.data),
+            access_list: request.access_list,
+            nonce: None,
+            gas_price: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            realistic_fees: false,
+            decode_trace: false,
+            format_trace: false,
+            state_diff: false,
+            trace_config: None,
+        }
+    }
+}
+
+/// Serves a single JSON-RPC request or a batch of them against a stateful simulation. Batches
+/// are executed in order while holding the session, so later requests see earlier transactions.
+pub async fn simulate_stateful_rpc(
+    param: Uuid,
+    body: Body,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+
+    // Malformed JSON is answered with a JSON-RPC error rather than rejected like other bodies
+    let body: Value = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(err) => {
+            return Ok(warp::reply::json(&JsonRpcResponse::new(
+                Value::Null,
+                Err(JsonRpcError::new(PARSE_ERROR, err.to_string())),
+            )))
+        }
+    };
+
+    let mut evm = session.lock().await;
+    let response = match body {
+        Value::Array(requests) if requests.is_empty() => {
+            serde_json::to_value(JsonRpcResponse::new(
+                Value::Null,
+                Err(JsonRpcError::new(INVALID_REQUEST, "empty batch")),
+            ))
+        }
+        Value::Array(requests) => {
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                responses.push(handle(&mut evm, request).await);
+            }
+            serde_json::to_value(responses)
+        }
+        request => serde_json::to_value(handle(&mut evm, request).await),
+    };
+
+    Ok(warp::reply::json(&response.unwrap_or_default()))
+}
+
+async fn handle(evm: &mut Evm, request: Value) -> JsonRpcResponse {
+    let request: JsonRpcRequest = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(err) => {
+            return JsonRpcResponse::new(
+                Value::Null,
+                Err(JsonRpcError::new(INVALID_REQUEST, err.to_string())),
+            )
+        }
+    };
+    let result = dispatch(evm, &request.method, &request.params).await;
+    JsonRpcResponse::new(request.id, result)
+}
+
+async fn dispatch(evm: &mut Evm, method: &str, params: &[Value]) -> Result<Value, JsonRpcError> {
+    // Block parameters are accepted but ignored, everything is served from the session's state
+    let result = match method {
+        "eth_chainId" => to_value(evm.get_chain_id()),
+        "net_version" => to_value(evm.get_chain_id().to_string()),
+        "eth_blockNumber" => to_value(evm.get_block()),
+        // Simulations run with a zero base fee
+        "eth_gasPrice" => to_value(Uint::zero()),
+        "eth_getBalance" => to_value(evm.get_balance(param(params, 0)?).map_err(server_error)?),
+        "eth_getTransactionCount" => to_value(U64::from(
+            evm.get_nonce(param(params, 0)?).map_err(server_error)?,
+        )),
+        "eth_getCode" => to_value(evm.get_code(param(params, 0)?).map_err(server_error)?),
+        "eth_getStorageAt" => {
+            // Slots are quantities, which aren't necessarily padded to 32 bytes
+            let slot: Uint = param(params, 1)?;
+            let mut bytes = [0u8; 32];
+            slot.to_big_endian(&mut bytes);
+            to_value(
+                evm.get_storage_at(param(params, 0)?, Hash::from(bytes))
+                    .map_err(server_error)?,
+            )
+        }
+        "eth_call" => {
+            let request: CallRequest = param(params, 0)?;
+            let gas_limit = request.gas.map_or(evm.get_gas_limit(), |gas| gas.as_u64());
+            let result = call(evm, request.into(), gas_limit).await?;
+            if !result.success {
+                return Err(execution_error(&result));
+            }
+            to_value(result.return_data)
+        }
+        "eth_estimateGas" => {
+            let request: CallRequest = param(params, 0)?;
+            let gas_limit = request.gas.map_or(evm.get_gas_limit(), |gas| gas.as_u64());
+            let call_request = CallRawRequest::from(request);
+            let estimate = evm
+                .estimate_gas(call_request.clone(), gas_limit)
+                .await
+                .map_err(server_error)?;
+            match estimate {
+                Some(estimate) => to_value(U64::from(estimate.gas_required)),
+                // Run it once more with the whole allowance to report why it fails
+                None => return Err(execution_error(&call(evm, call_request, gas_limit).await?)),
+            }
+        }
+        "eth_sendTransaction" => {
+            let request: CallRequest = param(params, 0)?;
+            let from = request
+                .from
+                .ok_or_else(|| JsonRpcError::new(INVALID_PARAMS, "missing from"))?;
+            let gas_limit = request.gas.map_or(evm.get_gas_limit(), |gas| gas.as_u64());
+            let chain_id = evm.get_chain_id().as_u64();
+
+            let mut transaction = TransactionRequest::new()
+                .from(from)
+                .gas(gas_limit)
+                .gas_price(Uint::zero())
+                .nonce(evm.get_nonce(from).map_err(server_error)?)
+                .chain_id(chain_id);
+            if let Some(to) = request.to {
+                transaction = transaction.to(to);
+            }
+            if let Some(value) = request.value {
+                transaction = transaction.value(value);
+            }
+            if let Some(data) = request.input.clone().or(request.data.clone()) {
+                transaction = transaction.data(data);
+            }
+
+            // There is no signature, so the transaction is hashed with a placeholder one, like
+            // anvil does for impersonated accounts
+            let signature = Signature {
+                r: Uint::one(),
+                s: Uint::one(),
+                v: chain_id * 2 + 35,
+            };
+            let transaction = Transaction {
+                hash: Hash::from(keccak256(transaction.rlp_signed(&signature))),
+                nonce: transaction.nonce.unwrap_or_default(),
+                from,
+                to: request.to,
+                value: request.value.unwrap_or_default(),
+                gas_price: Some(Uint::zero()),
+                gas: gas_limit.into(),
+                input: transaction.data.clone().unwrap_or_default(),
+                v: signature.v.into(),
+                r: signature.r,
+                s: signature.s,
+                chain_id: Some(chain_id.into()),
+                ..Default::default()
+            };
+
+            to_value(send(evm, transaction, request.into()).await?)
+        }
+        "eth_sendRawTransaction" => {
+            let raw: Bytes = param(params, 0)?;
+            let transaction = decode_raw_transaction(&raw)
+                .map_err(|err| JsonRpcError::new(INVALID_PARAMS, err.to_string()))?;
+            if let Some(chain_id) = transaction.chain_id {
+                if chain_id != evm.get_chain_id() {
+                    return Err(JsonRpcError::new(INVALID_PARAMS, "incorrect chain id"));
+                }
+            }
+
+            let request = transaction.clone().into();
+            to_value(send(evm, transaction, request).await?)
+        }
+        "eth_getTransactionByHash" => to_value(
+            evm.get_committed_transaction(param(params, 0)?)
+                .map(|committed| &committed.transaction),
+        ),
+        "eth_getTransactionReceipt" => to_value(
+            evm.get_committed_transaction(param(params, 0)?)
+                .map(|committed| &committed.receipt),
+        ),
+        _ => {
+            return Err(JsonRpcError::new(
+                METHOD_NOT_FOUND,
+                format!("method {method} is not supported"),
+            ))
+        }
+    };
+
+    Ok(result)
+}
+
+/// Runs a call without committing it, leaving the session's gas limit as it was
+async fn call(
+    evm: &mut Evm,
+    request: CallRawRequest,
+    gas_limit: u64,
+) -> Result<CallRawResult, JsonRpcError> {
+    let original_gas_limit = evm.get_gas_limit();
+    evm.set_gas_limit(gas_limit);
+    let result = evm.call_raw(request).await;
+    evm.set_gas_limit(original_gas_limit);
+    result.map_err(server_error)
+}
+
+/// Commits a transaction and keeps it along with its receipt for `eth_getTransactionByHash` and
+/// `eth_getTransactionReceipt`. Returns the transaction's hash.
+async fn send(
+    evm: &mut Evm,
+    mut transaction: Transaction,
+    request: CallRawRequest,
+) -> Result<Hash, JsonRpcError> {
+    let position = evm.get_transaction_count();
+    let result = commit(evm, request, transaction.gas.low_u64()).await?;
+
+    // The index is the transaction's position among all transactions committed to the session
+    let block_number = Some(U64::from(result.block_number));
+    let transaction_index = Some(U64::from(position));
+    transaction.block_number = block_number;
+    transaction.transaction_index = transaction_index;
+
+    let logs = result
+        .logs
+        .into_iter()
+        .enumerate()
+        .map(|(idx, log)| Log {
+            block_number,
+            transaction_hash: Some(transaction.hash),
+            transaction_index,
+            log_index: Some(Uint::from(idx)),
+            ..log
+        })
+        .collect();
+    let receipt = TransactionReceipt {
+        transaction_hash: transaction.hash,
+        transaction_index: U64::from(position),
+        block_number,
+        from: transaction.from,
+        to: transaction.to,
+        // Includes the gas of all transactions committed to the session before this one
+        cumulative_gas_used: Uint::from(evm.get_cumulative_gas_used()),
+        gas_used: Some(Uint::from(result.gas_used)),
+        contract_address: result.deployed_address,
+        logs,
+        status: Some(U64::from(u64::from(result.success))),
+        effective_gas_price: Some(effective_gas_price(
+            evm.get_block_base_fee(),
+            transaction.gas_price,
+            transaction.max_fee_per_gas,
+            transaction.max_priority_fee_per_gas,
+        )),
+        transaction_type: transaction.transaction_type,
+        ..Default::default()
+    };
+
+    let hash = transaction.hash;
+    evm.record_transaction(CommittedTransaction {
+        transaction,
+        receipt,
+    });
+    Ok(hash)
+}
+
+/// Commits a transaction, leaving the session's gas limit as it was. Failed transactions are
+/// still committed, like a reverted transaction that was included in a block.
+async fn commit(
+    evm: &mut Evm,
+    request: CallRawRequest,
+    gas_limit: u64,
+) -> Result<CallRawResult, JsonRpcError> {
+    let original_gas_limit = evm.get_gas_limit();
+    let result = evm.call_raw_committing(request, gas_limit).await;
+    evm.set_gas_limit(original_gas_limit);
+    result.map_err(server_error)
+}
+
+pub(crate) fn execution_error(result: &CallRawResult) -> JsonRpcError {
+    if result.exit_reason != InstructionResult::Revert {
+        return JsonRpcError::new(SERVER_ERROR, format!("{:?}", result.exit_reason));
+    }
+    let message = match &result.revert_reason {
+        Some(RevertReason::Error { message }) => format!("execution reverted: {message}"),
+        _ => "execution reverted".to_string(),
+    };
+    JsonRpcError {
+        code: EXECUTION_REVERTED,
+        message,
+        data: Some(to_value(&result.return_data)),
+    }
+}
+
+fn param<T: DeserializeOwned>(params: &[Value], idx: usize) -> Result<T, JsonRpcError> {
+    let value = params
+        .get(idx)
+        .ok_or_else(|| JsonRpcError::new(INVALID_PARAMS, format!("missing param {idx}")))?;
+    serde_json::from_value(value.clone())
+        .map_err(|err| JsonRpcError::new(INVALID_PARAMS, format!("invalid param {idx}: {err}")))
+}
+
+pub(crate) fn server_error(err: EvmError) -> JsonRpcError {
+    JsonRpcError::new(SERVER_ERROR, err.0.to_string())
+}
+
+fn to_value<T: Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
@@ -0,0 +1,153 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+use crate::evm::Evm;
+
+/// A stateful simulation: an EVM which is kept around across requests, along with when it was
+/// last used so that abandoned sessions can be reaped.
+pub struct Session {
+    evm: Mutex<Evm>,
+    last_used: std::sync::Mutex<Instant>,
+}
+
+impl Session {
+    pub fn new(evm: Evm) -> Self {
+        Session {
+            evm: Mutex::new(evm),
+            last_used: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Locks the session's EVM. The session counts as used until the returned guard is dropped.
+    pub async fn lock(&self) -> SessionGuard<'_> {
+        let evm = self.evm.lock().await;
+        self.touch();
+        SessionGuard { session: self, evm }
+    }
+
+    /// Locks the session's EVM without counting as a use, for looking at the session without
+    /// keeping it alive.
+    pub async fn peek(&self) -> MutexGuard<'_, Evm> {
+        self.evm.lock().await
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_used.lock().unwrap().elapsed()
+    }
+
+    /// Whether a request is currently using the session's EVM
+    pub fn is_busy(&self) -> bool {
+        self.evm.try_lock().is_err()
+    }
+
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+}
+
+pub struct SessionGuard<'a> {
+    session: &'a Session,
+    evm: MutexGuard<'a, Evm>,
+}
+
+impl Deref for SessionGuard<'_> {
+    type Target = Evm;
+
+    fn deref(&self) -> &Evm {
+        &self.evm
+    }
+}
+
+impl DerefMut for SessionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Evm {
+        &mut self.evm
+    }
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        self.session.touch();
+    }
+}
+
+/// Keeps the number of sessions within the limit while new sessions are still being created,
+/// which takes a while as their fork has to be spawned first.
+#[derive(Debug, Default)]
+pub struct SessionSlots {
+    creating: AtomicUsize,
+}
+
+impl SessionSlots {
+    /// Reserves a slot for a new session, unless the existing sessions and the ones being created
+    /// already reach `max_sessions`. The slot is held until the returned guard is dropped, which
+    /// has to happen after the new session has been inserted.
+    pub fn reserve(
+        &self,
+        sessions: &DashMap<Uuid, Arc<Session>>,
+        max_sessions: usize,
+    ) -> Option<SessionSlot<'_>> {
+        let creating = self.creating.fetch_add(1, Ordering::SeqCst);
+        let slot = SessionSlot {
+            creating: &self.creating,
+        };
+        (sessions.len() + creating < max_sessions).then_some(slot)
+    }
+}
+
+pub struct SessionSlot<'a> {
+    creating: &'a AtomicUsize,
+}
+
+impl Drop for SessionSlot<'_> {
+    fn drop(&mut self) {
+        self.creating.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Removes the sessions which haven't been used for longer than `ttl`, returning how many were
+/// removed. Sessions in use by a request are never removed.
+pub fn remove_idle_sessions(sessions: &DashMap<Uuid, Arc<Session>>, ttl: Duration) -> usize {
+    let before = sessions.len();
+    sessions.retain(|_, session| session.is_busy() || session.idle_for() <= ttl);
+    before.saturating_sub(sessions.len())
+}
+
+/// Periodically removes sessions which have been idle for longer than `ttl`.
+pub async fn reap_idle_sessions(sessions: Arc<DashMap<Uuid, Arc<Session>>>, ttl: Duration) {
+    let mut interval = tokio::time::interval(ttl.min(Duration::from_secs(60)));
+    loop {
+        interval.tick().await;
+        let removed = remove_idle_sessions(&sessions, ttl);
+        if removed > 0 {
+            log::info!(target: "ts::api", "Removed {} idle stateful simulations", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_slots() {
+        let sessions = DashMap::new();
+        let slots = SessionSlots::default();
+
+        let first = slots.reserve(&sessions, 2);
+        let second = slots.reserve(&sessions, 2);
+        assert!(first.is_some() && second.is_some());
+        // Sessions which are still being created count towards the limit
+        assert!(slots.reserve(&sessions, 2).is_none());
+
+        drop(first);
+        assert!(slots.reserve(&sessions, 2).is_some());
+        drop(second);
+        assert_eq!(slots.creating.load(Ordering::SeqCst), 0);
+    }
+}
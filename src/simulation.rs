@@ -2,43 +2,61 @@ use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use dashmap::mapref::one::RefMut;
 use ethers::abi::{Address, Hash, Uint};
 use ethers::core::types::Log;
 use ethers::types::transaction::eip2930::AccessList;
-use ethers::types::Bytes;
+use ethers::types::{Bytes, Transaction, U64};
+use ethers::utils::rlp::{Decodable, Rlp};
 use foundry_evm::CallKind;
 use revm::interpreter::InstructionResult;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
 use uuid::Uuid;
 use warp::reply::Json;
 use warp::Rejection;
 
+use crate::assets::{approval_for_all_tokens, asset_changes, AssetChanges, ERC1155_INTERFACE_ID};
+use crate::chains::ChainConfig;
 use crate::errors::{
-    IncorrectChainIdError, InvalidBlockNumbersError, MultipleChainIdsError, NoURLForChainIdError,
-    StateNotFound,
+    ExecutionRevertedError, IncorrectChainIdError, InvalidBlockNumbersError,
+    InvalidTransactionError, MultipleChainIdsError, NoURLForChainIdError, SnapshotNotFound,
+    StateNotFound, TooManySessionsError,
 };
 use crate::evm::StorageOverride;
+use crate::fees::{charged_gas_price, effective_gas_price, Fees};
+use crate::fork::Fork;
+use crate::revert::RevertReason;
+use crate::rpc::{execution_error, server_error, CallRequest, JsonRpcError};
+use crate::session::Session;
+use crate::state_diff::AccountDiff;
+use crate::state_dump::StateDump;
+use crate::tracer::{GethTrace, StructLog, TraceConfig};
 use crate::SharedSimulationState;
 
 use super::config::Config;
-use super::evm::{CallRawRequest, Evm};
+use super::evm::{decoded_function, CallRawRequest, Evm};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SimulationRequest {
     pub chain_id: u64,
     pub from: Address,
-    pub to: Address,
+    pub to: Option<Address>,
     pub data: Option<Bytes>,
-    pub gas_limit: u64,
+    pub gas_limit: Option<u64>,
     pub value: Option<PermissiveUint>,
+    pub gas_price: Option<PermissiveUint>,
+    pub max_fee_per_gas: Option<PermissiveUint>,
+    pub max_priority_fee_per_gas: Option<PermissiveUint>,
+    pub realistic_fees: Option<bool>,
+    pub nonce: Option<u64>,
     pub access_list: Option<AccessList>,
     pub block_number: Option<u64>,
     pub block_timestamp: Option<u64>,
+    pub block_overrides: Option<BlockOverrides>,
     pub state_overrides: Option<HashMap<Address, StateOverride>>,
     pub format_trace: Option<bool>,
+    pub include_state_diff: Option<bool>,
+    pub trace_config: Option<TraceConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -53,15 +71,48 @@ pub struct SimulationResponse {
     pub logs: Vec<Log>,
     pub exit_reason: InstructionResult,
     pub return_data: Bytes,
+    pub revert_reason: Option<RevertReason>,
+    pub deployed_address: Option<Address>,
+    pub deployed_code_size: Option<u64>,
+    pub state_diff: Option<HashMap<Address, AccountDiff>>,
+    pub struct_logs: Option<Vec<StructLog>>,
+    pub tracer_result: Option<GethTrace>,
+    pub asset_changes: AssetChanges,
+    #[serde(flatten)]
+    pub fees: Fees,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessListResponse {
+    pub access_list: AccessList,
+    pub gas_used_without_access_list: u64,
+    pub gas_used_with_access_list: u64,
+    pub success: bool,
+}
+
+/// Signed transactions to simulate in order, as they would be broadcast
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RawSimulationRequest {
+    pub chain_id: u64,
+    pub transactions: Vec<Bytes>,
+    pub block_number: Option<u64>,
+    pub block_timestamp: Option<u64>,
+    pub realistic_fees: Option<bool>,
+    pub format_trace: Option<bool>,
+    pub include_state_diff: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StatefulSimulationRequest {
     pub chain_id: u64,
-    pub gas_limit: u64,
+    pub gas_limit: Option<u64>,
     pub block_number: Option<u64>,
     pub block_timestamp: Option<u64>,
+    /// Seeds the session, as exported from another one
+    pub state: Option<StateDump>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -75,9 +126,52 @@ pub struct StatefulSimulationEndResponse {
     pub success: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StatefulSimulationSnapshotResponse {
+    pub snapshot_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StatefulSimulationRevertResponse {
+    pub success: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StatefulSimulationInfo {
+    pub stateful_simulation_id: Uuid,
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub block_timestamp: u64,
+    pub transaction_count: u64,
+    pub idle_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BalanceResponse {
+    pub balance: Uint,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NonceResponse {
+    pub nonce: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CodeResponse {
+    pub code: Bytes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StorageResponse {
+    pub value: Hash,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct StateOverride {
     pub balance: Option<PermissiveUint>,
+    #[serde(default, deserialize_with = "permissive_u64")]
     pub nonce: Option<u64>,
     pub code: Option<Bytes>,
     #[serde(flatten)]
@@ -96,6 +190,64 @@ pub enum State {
     },
 }
 
+/// Block environment overrides, in the shape of `eth_simulateV1`'s `blockOverrides`.
+/// `difficulty` is for chains which haven't moved to proof of stake and use it instead of
+/// `prevRandao`. `chainId` only changes what the `CHAINID` opcode returns.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockOverrides {
+    pub number: Option<U64>,
+    pub time: Option<U64>,
+    pub gas_limit: Option<U64>,
+    pub fee_recipient: Option<Address>,
+    pub prev_randao: Option<Hash>,
+    pub difficulty: Option<Uint>,
+    pub base_fee_per_gas: Option<Uint>,
+    pub chain_id: Option<U64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulateV1Request {
+    pub chain_id: u64,
+    pub block_number: Option<u64>,
+    pub block_state_calls: Vec<BlockStateCall>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockStateCall {
+    pub block_overrides: Option<BlockOverrides>,
+    pub state_overrides: Option<HashMap<Address, StateOverride>>,
+    #[serde(default)]
+    pub calls: Vec<CallRequest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulatedBlock {
+    pub number: U64,
+    pub timestamp: U64,
+    pub gas_limit: U64,
+    pub gas_used: U64,
+    pub miner: Address,
+    pub base_fee_per_gas: Uint,
+    pub mix_hash: Hash,
+    pub calls: Vec<SimulatedCall>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulatedCall {
+    /// `0x1` if the call succeeded, `0x0` otherwise
+    pub status: U64,
+    pub return_data: Bytes,
+    pub gas_used: U64,
+    pub logs: Vec<Log>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
 impl From<State> for StorageOverride {
     fn from(value: State) -> Self {
         let (slots, diff) = match value {
@@ -113,6 +265,28 @@ impl From<State> for StorageOverride {
     }
 }
 
+impl From<SimulationRequest> for CallRawRequest {
+    fn from(transaction: SimulationRequest) -> Self {
+        CallRawRequest {
+            from: transaction.from,
+            to: transaction.to,
+            value: transaction.value.map(Uint::from),
+            data: transaction.data,
+            access_list: transaction.access_list,
+            nonce: transaction.nonce,
+            gas_price: transaction.gas_price.map(Uint::from),
+            max_fee_per_gas: transaction.max_fee_per_gas.map(Uint::from),
+            max_priority_fee_per_gas: transaction.max_priority_fee_per_gas.map(Uint::from),
+            realistic_fees: transaction.realistic_fees.unwrap_or_default(),
+            // The call tree is always returned, with the functions of the calls
+            decode_trace: true,
+            format_trace: transaction.format_trace.unwrap_or_default(),
+            state_diff: transaction.include_state_diff.unwrap_or_default(),
+            trace_config: transaction.trace_config,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct CallTrace {
@@ -120,9 +294,18 @@ pub struct CallTrace {
     pub from: Address,
     pub to: Address,
     pub value: Uint,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub depth: usize,
+    pub input: Bytes,
+    pub output: Bytes,
+    pub gas_used: u64,
+    pub success: bool,
+    pub status: InstructionResult,
+    pub function: Option<String>,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(transparent)]
 pub struct PermissiveUint(pub Uint);
 
@@ -148,42 +331,172 @@ impl<'de> Deserialize<'de> for PermissiveUint {
     }
 }
 
-fn chain_id_to_fork_url(chain_id: u64) -> Result<String, Rejection> {
-    match chain_id {
-        // ethereum
-        1 => Ok("https://eth.llamarpc.com".to_string()),
-        5 => Ok("https://eth-goerli.g.alchemy.com/v2/demo".to_string()),
-        11155111 => Ok("https://eth-sepolia.g.alchemy.com/v2/demo".to_string()),
-        // polygon
-        137 => Ok("https://polygon-mainnet.g.alchemy.com/v2/demo".to_string()),
-        80001 => Ok("https://polygon-mumbai.g.alchemy.com/v2/demo".to_string()),
-        // avalanche
-        43114 => Ok("https://api.avax.network/ext/bc/C/rpc".to_string()),
-        43113 => Ok("https://api.avax-test.network/ext/bc/C/rpc".to_string()),
-        // fantom
-        250 => Ok("https://rpcapi.fantom.network/".to_string()),
-        4002 => Ok("https://rpc.testnet.fantom.network/".to_string()),
-        // xdai
-        100 => Ok("https://rpc.xdaichain.com/".to_string()),
-        // bsc
-        56 => Ok("https://bsc-dataseed.binance.org/".to_string()),
-        97 => Ok("https://data-seed-prebsc-1-s1.binance.org:8545/".to_string()),
-        // arbitrum
-        42161 => Ok("https://arb1.arbitrum.io/rpc".to_string()),
-        421613 => Ok("https://goerli-rollup.arbitrum.io/rpc".to_string()),
-        // optimism
-        10 => Ok("https://mainnet.optimism.io/".to_string()),
-        420 => Ok("https://goerli.optimism.io/".to_string()),
-        _ => Err(NoURLForChainIdError.into()),
-    }
-}
-
-async fn run(
+/// Deserializes an optional `u64` from a number, or from a decimal or hex string
+pub(crate) fn permissive_u64<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u64),
+        String(PermissiveUint),
+    }
+
+    match Option::<NumberOrString>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(NumberOrString::Number(value)) => Ok(Some(value)),
+        Some(NumberOrString::String(PermissiveUint(value))) => {
+            if value > Uint::from(u64::MAX) {
+                return Err(serde::de::Error::custom("value does not fit in a u64"));
+            }
+            Ok(Some(value.as_u64()))
+        }
+    }
+}
+
+/// Decodes an RLP encoded signed transaction, legacy or typed, and recovers its sender
+pub fn decode_raw_transaction(raw: &[u8]) -> eyre::Result<Transaction> {
+    let mut transaction = Transaction::decode(&Rlp::new(raw))?;
+    transaction.recover_from_mut()?;
+    Ok(transaction)
+}
+
+/// Builds the request to simulate a signed transaction with, taking its nonce, gas limit, fees
+/// and access list from the payload.
+fn raw_transaction_request(
+    request: &RawSimulationRequest,
+    raw: &[u8],
+) -> Result<SimulationRequest, Rejection> {
+    let transaction =
+        decode_raw_transaction(raw).map_err(|_| warp::reject::custom(InvalidTransactionError()))?;
+
+    Ok(SimulationRequest {
+        realistic_fees: Some(request.realistic_fees.unwrap_or(true)),
+        block_number: request.block_number,
+        block_timestamp: request.block_timestamp,
+        format_trace: request.format_trace,
+        include_state_diff: request.include_state_diff,
+        ..signed_transaction_request(request.chain_id, transaction)?
+    })
+}
+
+/// Simulates a signed transaction as it was sent, with its nonce, gas limit, fees and access list,
+/// charging the sender for gas
+pub(crate) fn signed_transaction_request(
+    chain_id: u64,
+    transaction: Transaction,
+) -> Result<SimulationRequest, Rejection> {
+    if let Some(transaction_chain_id) = transaction.chain_id {
+        if transaction_chain_id != Uint::from(chain_id) {
+            return Err(warp::reject::custom(IncorrectChainIdError()));
+        }
+    }
+    let (Ok(gas_limit), Ok(nonce)) = (
+        u64::try_from(transaction.gas),
+        u64::try_from(transaction.nonce),
+    ) else {
+        return Err(warp::reject::custom(InvalidTransactionError()));
+    };
+
+    Ok(SimulationRequest {
+        chain_id,
+        from: transaction.from,
+        to: transaction.to,
+        data: Some(transaction.input),
+        gas_limit: Some(gas_limit),
+        value: Some(PermissiveUint(transaction.value)),
+        gas_price: transaction.gas_price.map(PermissiveUint),
+        max_fee_per_gas: transaction.max_fee_per_gas.map(PermissiveUint),
+        max_priority_fee_per_gas: transaction.max_priority_fee_per_gas.map(PermissiveUint),
+        realistic_fees: Some(true),
+        nonce: Some(nonce),
+        access_list: transaction.access_list,
+        block_number: None,
+        block_timestamp: None,
+        block_overrides: None,
+        state_overrides: None,
+        format_trace: None,
+        include_state_diff: None,
+        trace_config: None,
+    })
+}
+
+/// Looks up how to reach the chain in the chains config. Chains without an explorer key of their
+/// own use the global Etherscan key.
+pub(crate) fn chain_config(config: &Config, chain_id: u64) -> Result<ChainConfig, Rejection> {
+    let mut chain = config
+        .chains
+        .get(&chain_id)
+        .cloned()
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
+    if chain.explorer_key.is_none() {
+        chain.explorer_key = config.etherscan_key.clone();
+    }
+    Ok(chain)
+}
+
+/// Forks the chain through its relay, so that both the environment and the state of the fork are
+/// fetched with failover between the chain's RPC endpoints. Local chains aren't forked, but start
+/// from their state file instead.
+pub(crate) fn fork(
+    state: &SharedSimulationState,
+    chain_id: u64,
+    chain: &ChainConfig,
+    block_number: Option<u64>,
+) -> Result<Fork, Rejection> {
+    if let Some(local) = &chain.local {
+        return Ok(state
+            .forks
+            .local(chain_id, local, chain.default_gas_limit, block_number));
+    }
+
+    let relay_url = state.providers.relay_url(chain_id, chain)?;
+    Ok(state.forks.fork(chain_id, relay_url, block_number)?)
+}
+
+/// Creates an EVM on a fork of the chain at the block the transaction asks for, checking that the
+/// fork is on the requested chain and moving the block to the requested timestamp. Bundles are
+/// set up by their first transaction.
+async fn transaction_evm(
+    transaction: &SimulationRequest,
+    tracing: bool,
+    config: &Config,
+    state: &SharedSimulationState,
+) -> Result<Evm, Rejection> {
+    let chain = chain_config(config, transaction.chain_id)?;
+    let fork = fork(
+        state,
+        transaction.chain_id,
+        &chain,
+        transaction.block_number,
+    )?;
+    let mut evm = Evm::new(
+        None,
+        fork,
+        transaction.gas_limit.unwrap_or(chain.default_gas_limit),
+        tracing,
+        &chain,
+    );
+
+    if evm.get_chain_id() != Uint::from(transaction.chain_id) {
+        return Err(warp::reject::custom(IncorrectChainIdError()));
+    }
+
+    if let Some(timestamp) = transaction.block_timestamp {
+        evm.set_block_timestamp(timestamp)
+            .await
+            .expect("failed to set block timestamp");
+    }
+
+    Ok(evm)
+}
+
+fn apply_state_overrides(
     evm: &mut Evm,
-    transaction: SimulationRequest,
-    commit: bool,
-) -> Result<SimulationResponse, Rejection> {
-    for (address, state_override) in transaction.state_overrides.into_iter().flatten() {
+    state_overrides: Option<HashMap<Address, StateOverride>>,
+) -> Result<(), Rejection> {
+    for (address, state_override) in state_overrides.into_iter().flatten() {
         evm.override_account(
             address,
             state_override.balance.map(Uint::from),
@@ -193,61 +506,120 @@ async fn run(
         )?;
     }
 
-    let call = CallRawRequest {
-        from: transaction.from,
-        to: transaction.to,
-        value: transaction.value.map(Uint::from),
-        data: transaction.data,
-        access_list: transaction.access_list,
-        format_trace: transaction.format_trace.unwrap_or_default(),
+    Ok(())
+}
+
+async fn apply_block_overrides(evm: &mut Evm, block_overrides: Option<&BlockOverrides>) {
+    let Some(block_overrides) = block_overrides else {
+        return;
     };
+    if let Some(number) = block_overrides.number {
+        evm.set_block(number.as_u64())
+            .await
+            .expect("Failed to set block number");
+    }
+    if let Some(time) = block_overrides.time {
+        evm.set_block_timestamp(time.as_u64())
+            .await
+            .expect("Failed to set block timestamp");
+    }
+    if let Some(gas_limit) = block_overrides.gas_limit {
+        evm.set_block_gas_limit(gas_limit.as_u64());
+    }
+    if let Some(fee_recipient) = block_overrides.fee_recipient {
+        evm.set_coinbase(fee_recipient);
+    }
+    if let Some(prev_randao) = block_overrides.prev_randao {
+        evm.set_prevrandao(prev_randao);
+    }
+    if let Some(difficulty) = block_overrides.difficulty {
+        evm.set_difficulty(difficulty);
+    }
+    if let Some(base_fee) = block_overrides.base_fee_per_gas {
+        evm.set_base_fee(base_fee);
+    }
+    if let Some(chain_id) = block_overrides.chain_id {
+        evm.set_chain_id_override(chain_id.as_u64());
+    }
+}
+
+pub(crate) async fn run(
+    evm: &mut Evm,
+    mut transaction: SimulationRequest,
+    commit: bool,
+) -> Result<SimulationResponse, Rejection> {
+    apply_block_overrides(evm, transaction.block_overrides.as_ref()).await;
+    apply_state_overrides(evm, transaction.state_overrides.take())?;
+
+    let gas_limit = transaction.gas_limit.unwrap_or(evm.get_default_gas_limit());
+    let base_fee = evm.get_block_base_fee();
+    // Transactions charged realistic fees without any of their own pay the base fee plus their
+    // priority fee
+    let gas_price = match (transaction.gas_price, transaction.max_fee_per_gas) {
+        (None, None) if transaction.realistic_fees.unwrap_or_default() => Some(charged_gas_price(
+            base_fee,
+            None,
+            None,
+            transaction.max_priority_fee_per_gas.map(Uint::from),
+        )),
+        (gas_price, _) => gas_price.map(Uint::from),
+    };
+    let effective_gas_price = effective_gas_price(
+        base_fee,
+        gas_price,
+        transaction.max_fee_per_gas.map(Uint::from),
+        transaction.max_priority_fee_per_gas.map(Uint::from),
+    );
+    let call = CallRawRequest::from(transaction);
     let result = if commit {
-        evm.call_raw_committing(call, transaction.gas_limit).await?
+        evm.call_raw_committing(call, gas_limit).await?
     } else {
+        evm.set_gas_limit(gas_limit);
         evm.call_raw(call).await?
     };
 
+    let trace = result.trace.unwrap_or_default();
+    let erc1155_tokens = approval_for_all_tokens(&result.logs)
+        .into_iter()
+        .filter(|token| evm.supports_interface(*token, ERC1155_INTERFACE_ID))
+        .collect();
+    let asset_changes = asset_changes(&result.logs, &trace, &erc1155_tokens);
+
+    let mut trace: Vec<CallTrace> = trace.arena.into_iter().map(CallTrace::from).collect();
+    // The decoded arena has the same layout as the raw one, it just has the calls decoded
+    if let Some(decoded_trace) = result.decoded_trace {
+        for (call, node) in trace.iter_mut().zip(decoded_trace.arena.iter()) {
+            call.function = decoded_function(node);
+        }
+    }
+
     Ok(SimulationResponse {
         simulation_id: 1,
         gas_used: result.gas_used,
         block_number: result.block_number,
         success: result.success,
-        trace: result
-            .trace
-            .unwrap_or_default()
-            .arena
-            .into_iter()
-            .map(CallTrace::from)
-            .collect(),
+        trace,
         logs: result.logs,
         exit_reason: result.exit_reason,
         formatted_trace: result.formatted_trace,
         return_data: result.return_data,
+        revert_reason: result.revert_reason,
+        deployed_address: result.deployed_address,
+        deployed_code_size: result.deployed_code_size,
+        state_diff: result.state_diff,
+        struct_logs: result.struct_logs,
+        tracer_result: result.tracer_result,
+        asset_changes,
+        fees: Fees::new(base_fee, effective_gas_price, result.gas_used),
     })
 }
 
-pub async fn simulate(transaction: SimulationRequest, config: Config) -> Result<Json, Rejection> {
-    let fork_url = config
-        .fork_url
-        .unwrap_or(chain_id_to_fork_url(transaction.chain_id)?);
-    let mut evm = Evm::new(
-        None,
-        fork_url,
-        transaction.block_number,
-        transaction.gas_limit,
-        true,
-        config.etherscan_key,
-    );
-
-    if evm.get_chain_id() != Uint::from(transaction.chain_id) {
-        return Err(warp::reject::custom(IncorrectChainIdError()));
-    }
-
-    if let Some(timestamp) = transaction.block_timestamp {
-        evm.set_block_timestamp(timestamp)
-            .await
-            .expect("failed to set block timestamp");
-    }
+pub async fn simulate(
+    transaction: SimulationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let mut evm = transaction_evm(&transaction, true, &config, &state).await?;
 
     let response = run(&mut evm, transaction, false).await?;
 
@@ -257,35 +629,224 @@ pub async fn simulate(transaction: SimulationRequest, config: Config) -> Result<
 pub async fn simulate_bundle(
     transactions: Vec<SimulationRequest>,
     config: Config,
+    state: Arc<SharedSimulationState>,
 ) -> Result<Json, Rejection> {
     let first_chain_id = transactions[0].chain_id;
     let first_block_number = transactions[0].block_number;
-    let first_block_timestamp = transactions[0].block_timestamp;
 
-    let fork_url = config
-        .fork_url
-        .unwrap_or(chain_id_to_fork_url(first_chain_id)?);
-    let mut evm = Evm::new(
-        None,
-        fork_url,
-        first_block_number,
-        transactions[0].gas_limit,
-        true,
-        config.etherscan_key,
-    );
+    let mut evm = transaction_evm(&transactions[0], true, &config, &state).await?;
 
-    if evm.get_chain_id() != Uint::from(first_chain_id) {
+    let mut response = Vec::with_capacity(transactions.len());
+    for transaction in transactions {
+        if transaction.chain_id != first_chain_id {
+            return Err(warp::reject::custom(MultipleChainIdsError()));
+        }
+        if transaction.block_number != first_block_number {
+            let tx_block = transaction
+                .block_number
+                .expect("Transaction has no block number");
+            if transaction.block_number < first_block_number || tx_block < evm.get_block().as_u64()
+            {
+                return Err(warp::reject::custom(InvalidBlockNumbersError()));
+            }
+            evm.set_block(tx_block)
+                .await
+                .expect("Failed to set block number");
+            evm.set_block_timestamp(evm.get_block_timestamp().as_u64() + 12)
+                .await
+                .expect("Failed to set block timestamp");
+        }
+        response.push(run(&mut evm, transaction, true).await?);
+    }
+
+    Ok(warp::reply::json(&response))
+}
+
+/// The most blocks `eth_simulateV1` simulates on top of its base block, including skipped ones,
+/// like geth
+const MAX_SIMULATED_BLOCKS: u64 = 256;
+
+pub async fn simulate_v1(
+    request: SimulateV1Request,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let chain = chain_config(&config, request.chain_id)?;
+    let fork = fork(&state, request.chain_id, &chain, request.block_number)?;
+    let gas_limit: u64 = fork.env.block.gas_limit.saturating_to();
+    let mut evm = Evm::new(None, fork, gas_limit, false, &chain);
+
+    if evm.get_chain_id() != Uint::from(request.chain_id) {
         return Err(warp::reject::custom(IncorrectChainIdError()));
     }
 
-    if let Some(timestamp) = first_block_timestamp {
-        evm.set_block_timestamp(timestamp)
+    let mut number = evm.get_block().as_u64();
+    let mut timestamp = evm.get_block_timestamp().as_u64();
+    let last_number = number + MAX_SIMULATED_BLOCKS;
+    let mut blocks = Vec::with_capacity(request.block_state_calls.len());
+    for block in request.block_state_calls {
+        // Each block builds on the previous one, by default one block and 12 seconds later
+        let block_overrides = block.block_overrides.unwrap_or_default();
+        let block_number = block_overrides
+            .number
+            .map_or(number + 1, |number| number.as_u64());
+        if block_number <= number || block_number > last_number {
+            return Err(warp::reject::custom(InvalidBlockNumbersError()));
+        }
+
+        // Skipped block numbers are returned as empty blocks, 12 seconds apart
+        for gap_number in number + 1..block_number {
+            timestamp += 12;
+            blocks.push(SimulatedBlock {
+                number: gap_number.into(),
+                timestamp: timestamp.into(),
+                gas_limit: evm.get_block_gas_limit().into(),
+                gas_used: U64::zero(),
+                miner: evm.get_coinbase(),
+                base_fee_per_gas: evm.get_base_fee(),
+                mix_hash: evm.get_prevrandao(),
+                calls: Vec::new(),
+            });
+        }
+
+        let block_timestamp = block_overrides
+            .time
+            .map_or(timestamp + 12, |time| time.as_u64());
+        if block_timestamp <= timestamp {
+            return Err(warp::reject::custom(InvalidBlockNumbersError()));
+        }
+        evm.set_block(block_number)
             .await
-            .expect("failed to set block timestamp");
+            .expect("Failed to set block number");
+        evm.set_block_timestamp(block_timestamp)
+            .await
+            .expect("Failed to set block timestamp");
+        apply_block_overrides(&mut evm, Some(&block_overrides)).await;
+        apply_state_overrides(&mut evm, block.state_overrides)?;
+
+        let block_gas_limit = evm.get_block_gas_limit();
+        let mut gas_used = 0;
+        let mut log_index = 0u64;
+        let mut calls = Vec::with_capacity(block.calls.len());
+        for (transaction_index, call) in block.calls.into_iter().enumerate() {
+            // Calls without a gas limit can use whatever is left in the block
+            let call_gas_limit = call
+                .gas
+                .map_or(block_gas_limit.saturating_sub(gas_used), |gas| gas.as_u64());
+            // A call which can't be executed, like one with the wrong nonce, fails on its own
+            // rather than failing the whole simulation
+            let result = match evm.call_raw_committing(call.into(), call_gas_limit).await {
+                Ok(result) => result,
+                Err(err) => {
+                    calls.push(SimulatedCall {
+                        status: U64::zero(),
+                        return_data: Bytes::default(),
+                        gas_used: U64::zero(),
+                        logs: Vec::new(),
+                        error: Some(server_error(err)),
+                    });
+                    continue;
+                }
+            };
+            gas_used += result.gas_used;
+
+            let error = (!result.success).then(|| execution_error(&result));
+            let mut logs = result.logs;
+            for log in &mut logs {
+                log.block_number = Some(block_number.into());
+                log.transaction_index = Some(U64::from(transaction_index as u64));
+                log.log_index = Some(Uint::from(log_index));
+                log_index += 1;
+            }
+            calls.push(SimulatedCall {
+                status: U64::from(result.success as u64),
+                return_data: result.return_data,
+                gas_used: result.gas_used.into(),
+                logs,
+                error,
+            });
+        }
+
+        blocks.push(SimulatedBlock {
+            number: block_number.into(),
+            timestamp: block_timestamp.into(),
+            gas_limit: block_gas_limit.into(),
+            gas_used: gas_used.into(),
+            miner: evm.get_coinbase(),
+            base_fee_per_gas: evm.get_base_fee(),
+            mix_hash: evm.get_prevrandao(),
+            calls,
+        });
+        number = block_number;
+        timestamp = block_timestamp;
     }
 
+    Ok(warp::reply::json(&blocks))
+}
+
+/// Simulates signed transactions as a bundle, with their senders recovered from the signatures
+pub async fn simulate_raw(
+    request: RawSimulationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    if request.transactions.is_empty() {
+        return Err(warp::reject::custom(InvalidTransactionError()));
+    }
+    let transactions = request
+        .transactions
+        .iter()
+        .map(|raw| raw_transaction_request(&request, raw))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    simulate_bundle(transactions, config, state).await
+}
+
+async fn estimate(
+    evm: &mut Evm,
+    mut transaction: SimulationRequest,
+) -> Result<GasEstimateResponse, Rejection> {
+    apply_block_overrides(evm, transaction.block_overrides.as_ref()).await;
+    apply_state_overrides(evm, transaction.state_overrides.take())?;
+
+    let gas_limit = transaction.gas_limit.unwrap_or(evm.get_default_gas_limit());
+    let estimate = evm
+        .estimate_gas(CallRawRequest::from(transaction), gas_limit)
+        .await?
+        .ok_or_else(|| warp::reject::custom(ExecutionRevertedError()))?;
+
+    Ok(GasEstimateResponse {
+        gas_used: estimate.gas_used,
+        gas_required: estimate.gas_required,
+    })
+}
+
+pub async fn estimate_gas(
+    transaction: SimulationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let mut evm = transaction_evm(&transaction, false, &config, &state).await?;
+
+    let response = estimate(&mut evm, transaction).await?;
+
+    Ok(warp::reply::json(&response))
+}
+
+/// Estimates each transaction of the bundle against the state left by the transactions before
+/// it, which are committed with their own gas limit.
+pub async fn estimate_gas_bundle(
+    transactions: Vec<SimulationRequest>,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let first_chain_id = transactions[0].chain_id;
+    let first_block_number = transactions[0].block_number;
+
+    let mut evm = transaction_evm(&transactions[0], false, &config, &state).await?;
+
     let mut response = Vec::with_capacity(transactions.len());
-    for transaction in transactions {
+    for mut transaction in transactions {
         if transaction.chain_id != first_chain_id {
             return Err(warp::reject::custom(MultipleChainIdsError()));
         }
@@ -304,35 +865,83 @@ pub async fn simulate_bundle(
                 .await
                 .expect("Failed to set block timestamp");
         }
-        response.push(run(&mut evm, transaction, true).await?);
+        // Apply the overrides once, so they are in place for both the estimate and the commit
+        apply_state_overrides(&mut evm, transaction.state_overrides.take())?;
+        response.push(estimate(&mut evm, transaction.clone()).await?);
+        run(&mut evm, transaction, true).await?;
     }
 
     Ok(warp::reply::json(&response))
 }
 
+pub async fn create_access_list(
+    mut transaction: SimulationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let mut evm = transaction_evm(&transaction, false, &config, &state).await?;
+
+    apply_block_overrides(&mut evm, transaction.block_overrides.as_ref()).await;
+    apply_state_overrides(&mut evm, transaction.state_overrides.take())?;
+
+    let result = evm
+        .create_access_list(CallRawRequest::from(transaction))
+        .await?;
+
+    let response = AccessListResponse {
+        access_list: result.access_list,
+        gas_used_without_access_list: result.gas_used_without_access_list,
+        gas_used_with_access_list: result.gas_used_with_access_list,
+        success: result.success,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
 pub async fn simulate_stateful_new(
     stateful_simulation_request: StatefulSimulationRequest,
     config: Config,
     state: Arc<SharedSimulationState>,
 ) -> Result<Json, Rejection> {
-    let fork_url = config
-        .fork_url
-        .unwrap_or(chain_id_to_fork_url(stateful_simulation_request.chain_id)?);
+    let Some(_slot) = state
+        .session_slots
+        .reserve(&state.evms, config.max_sessions)
+    else {
+        return Err(warp::reject::custom(TooManySessionsError()));
+    };
+
+    // A stateful simulation seeded from a dump is forked at the dump's block by default, so that
+    // the accounts which aren't in the dump are read from the block it was taken at
+    let block_number = stateful_simulation_request.block_number.or_else(|| {
+        let dump = stateful_simulation_request.state.as_ref()?;
+        Some(dump.block.as_ref()?.number?.0.as_u64())
+    });
+    let chain = chain_config(&config, stateful_simulation_request.chain_id)?;
+    let fork = fork(
+        &state,
+        stateful_simulation_request.chain_id,
+        &chain,
+        block_number,
+    )?;
     let mut evm = Evm::new(
         None,
-        fork_url,
-        stateful_simulation_request.block_number,
-        stateful_simulation_request.gas_limit,
+        fork,
+        stateful_simulation_request
+            .gas_limit
+            .unwrap_or(chain.default_gas_limit),
         true,
-        config.etherscan_key,
+        &chain,
     );
 
+    if let Some(dump) = &stateful_simulation_request.state {
+        evm.load_state(dump);
+    }
     if let Some(timestamp) = stateful_simulation_request.block_timestamp {
         evm.set_block_timestamp(timestamp).await?;
     }
 
     let new_id = Uuid::new_v4();
-    state.evms.insert(new_id, Arc::new(Mutex::new(evm)));
+    state.evms.insert(new_id, Arc::new(Session::new(evm)));
 
     let response = StatefulSimulationResponse {
         stateful_simulation_id: new_id,
@@ -364,15 +973,9 @@ pub async fn simulate_stateful(
 
     let mut response = Vec::with_capacity(transactions.len());
 
-    // Get a mutable reference to the EVM here.
-    let evm_ref_mut: RefMut<'_, Uuid, Arc<Mutex<Evm>>> = state
-        .evms
-        .get_mut(&param)
-        .ok_or_else(warp::reject::not_found)?;
-
-    // Dereference to obtain the EVM.
-    let evm = evm_ref_mut.value();
-    let mut evm = evm.lock().await;
+    // Clone the session out of the map, so the map isn't locked while simulating
+    let session = get_session(&state, param)?;
+    let mut evm = session.lock().await;
 
     if evm.get_chain_id() != Uint::from(first_chain_id) {
         return Err(warp::reject::custom(IncorrectChainIdError()));
@@ -405,3 +1008,178 @@ pub async fn simulate_stateful(
 
     Ok(warp::reply::json(&response))
 }
+
+pub(crate) fn get_session(
+    state: &SharedSimulationState,
+    id: Uuid,
+) -> Result<Arc<Session>, Rejection> {
+    state
+        .evms
+        .get(&id)
+        .map(|session| session.value().clone())
+        .ok_or_else(|| warp::reject::custom(StateNotFound()))
+}
+
+pub async fn simulate_stateful_snapshot(
+    param: Uuid,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let mut evm = session.lock().await;
+
+    let response = StatefulSimulationSnapshotResponse {
+        snapshot_id: evm.snapshot(),
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn simulate_stateful_revert(
+    param: Uuid,
+    snapshot_id: u64,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let mut evm = session.lock().await;
+
+    if !evm.revert(snapshot_id) {
+        return Err(warp::reject::custom(SnapshotNotFound()));
+    }
+
+    let response = StatefulSimulationRevertResponse { success: true };
+
+    Ok(warp::reply::json(&response))
+}
+
+async fn session_info(id: Uuid, session: &Session) -> StatefulSimulationInfo {
+    let idle_seconds = session.idle_for().as_secs();
+    let evm = session.peek().await;
+
+    StatefulSimulationInfo {
+        stateful_simulation_id: id,
+        chain_id: evm.get_chain_id().as_u64(),
+        block_number: evm.get_block().as_u64(),
+        block_timestamp: evm.get_block_timestamp().as_u64(),
+        transaction_count: evm.get_transaction_count(),
+        idle_seconds,
+    }
+}
+
+pub async fn simulate_stateful_list(state: Arc<SharedSimulationState>) -> Result<Json, Rejection> {
+    // Collect the sessions first, so the map isn't locked while waiting for busy sessions
+    let sessions: Vec<(Uuid, Arc<Session>)> = state
+        .evms
+        .iter()
+        .map(|session| (*session.key(), session.value().clone()))
+        .collect();
+
+    let mut response = Vec::with_capacity(sessions.len());
+    for (id, session) in sessions {
+        response.push(session_info(id, &session).await);
+    }
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn simulate_stateful_info(
+    param: Uuid,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let response = session_info(param, &session).await;
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn simulate_stateful_balance(
+    param: Uuid,
+    address: Address,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let evm = session.lock().await;
+
+    let response = BalanceResponse {
+        balance: evm.get_balance(address)?,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn simulate_stateful_nonce(
+    param: Uuid,
+    address: Address,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let evm = session.lock().await;
+
+    let response = NonceResponse {
+        nonce: evm.get_nonce(address)?,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn simulate_stateful_code(
+    param: Uuid,
+    address: Address,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let evm = session.lock().await;
+
+    let response = CodeResponse {
+        code: evm.get_code(address)?,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn simulate_stateful_storage(
+    param: Uuid,
+    address: Address,
+    slot: Hash,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let evm = session.lock().await;
+
+    let response = StorageResponse {
+        value: evm.get_storage_at(address, slot)?,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn simulate_stateful_state(
+    param: Uuid,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let evm = session.lock().await;
+
+    Ok(warp::reply::json(&evm.dump_state()))
+}
+
+/// Simulates a transaction against the session's state without changing it. State overrides
+/// only apply to this call.
+pub async fn simulate_stateful_call(
+    param: Uuid,
+    transaction: SimulationRequest,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let session = get_session(&state, param)?;
+    let mut evm = session.lock().await;
+
+    if evm.get_chain_id() != Uint::from(transaction.chain_id) {
+        return Err(warp::reject::custom(IncorrectChainIdError()));
+    }
+
+    // State overrides are committed to the backend, so snapshot to be able to undo them
+    let snapshot_id = evm.snapshot();
+    let response = run(&mut evm, transaction, false).await;
+    evm.revert(snapshot_id);
+
+    Ok(warp::reply::json(&response?))
+}
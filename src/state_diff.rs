@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use ethers::abi::{Address, Hash, Uint};
+use foundry_evm::utils::{b160_to_h160, b256_to_h256, ru256_to_u256};
+use revm::db::DatabaseRef;
+use revm::primitives::State;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Diff<T> {
+    pub from: T,
+    pub to: T,
+}
+
+impl<T: PartialEq> Diff<T> {
+    fn new(from: T, to: T) -> Option<Self> {
+        (from != to).then_some(Diff { from, to })
+    }
+}
+
+/// The changes a transaction made to a single account. Fields which didn't change are omitted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountDiff {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Diff<Uint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Diff<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_hash: Option<Diff<Hash>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub storage: HashMap<Hash, Diff<Hash>>,
+}
+
+impl AccountDiff {
+    fn is_empty(&self) -> bool {
+        self.balance.is_none()
+            && self.nonce.is_none()
+            && self.code_hash.is_none()
+            && self.storage.is_empty()
+    }
+}
+
+/// Diffs the accounts in a changeset against their state in `db`, which must not have had the
+/// changeset committed yet. Accounts without any changes are left out.
+pub fn state_diff<DB: DatabaseRef>(db: &DB, changeset: &State) -> HashMap<Address, AccountDiff> {
+    changeset
+        .iter()
+        .filter_map(|(address, account)| {
+            let before = db.basic(*address).ok().flatten().unwrap_or_default();
+
+            let diff = AccountDiff {
+                balance: Diff::new(
+                    ru256_to_u256(before.balance),
+                    ru256_to_u256(account.info.balance),
+                ),
+                nonce: Diff::new(before.nonce, account.info.nonce),
+                code_hash: Diff::new(
+                    b256_to_h256(before.code_hash),
+                    b256_to_h256(account.info.code_hash),
+                ),
+                storage: account
+                    .storage
+                    .iter()
+                    .filter_map(|(slot, value)| {
+                        let diff = Diff::new(
+                            Hash::from(value.original_value.to_be_bytes::<32>()),
+                            Hash::from(value.present_value.to_be_bytes::<32>()),
+                        )?;
+                        Some((Hash::from(slot.to_be_bytes::<32>()), diff))
+                    })
+                    .collect(),
+            };
+
+            (!diff.is_empty()).then(|| (b160_to_h160(*address), diff))
+        })
+        .collect()
+}
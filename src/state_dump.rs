@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use ethers::abi::{Address, Hash};
+use ethers::types::Bytes;
+use eyre::Context;
+use foundry_evm::utils::{
+    b160_to_h160, b256_to_h256, h160_to_b160, h256_to_b256, ru256_to_u256, u256_to_ru256,
+};
+use revm::db::{AccountState, CacheDB};
+use revm::primitives::{
+    Account, AccountInfo, BlockEnv, Bytecode, StorageSlot, B160, U256 as rU256,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::simulation::{permissive_u64, PermissiveUint};
+
+/// Accounts and a block environment, in the JSON format written by `anvil --dump-state`. Geth
+/// genesis files are read into it as well.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct StateDump {
+    pub block: Option<DumpBlock>,
+    #[serde(default)]
+    pub accounts: BTreeMap<Address, DumpAccount>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DumpAccount {
+    #[serde(default, deserialize_with = "permissive_u64")]
+    pub nonce: Option<u64>,
+    pub balance: Option<PermissiveUint>,
+    pub code: Option<Bytes>,
+    #[serde(default)]
+    pub storage: BTreeMap<PermissiveUint, PermissiveUint>,
+    /// The account self-destructed, so it has no balance, nonce, code or storage left. Not part
+    /// of anvil's format.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub destroyed: bool,
+    /// The slots which aren't in `storage` are empty, rather than read from the forked block.
+    /// Not part of anvil's format.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub storage_cleared: bool,
+}
+
+/// The block environment of a state dump, with the field names of revm's `BlockEnv`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DumpBlock {
+    pub number: Option<PermissiveUint>,
+    pub coinbase: Option<Address>,
+    pub timestamp: Option<PermissiveUint>,
+    pub difficulty: Option<PermissiveUint>,
+    pub prevrandao: Option<Hash>,
+    pub basefee: Option<PermissiveUint>,
+    pub gas_limit: Option<PermissiveUint>,
+}
+
+impl DumpBlock {
+    /// Sets the fields of the block environment which are in the dump
+    pub fn apply(&self, block: &mut BlockEnv) {
+        let uint = |value: &PermissiveUint| u256_to_ru256(value.0);
+        if let Some(number) = &self.number {
+            block.number = uint(number);
+        }
+        if let Some(coinbase) = self.coinbase {
+            block.coinbase = h160_to_b160(coinbase);
+        }
+        if let Some(timestamp) = &self.timestamp {
+            block.timestamp = uint(timestamp);
+        }
+        if let Some(difficulty) = &self.difficulty {
+            block.difficulty = uint(difficulty);
+        }
+        if let Some(prevrandao) = self.prevrandao {
+            block.prevrandao = Some(h256_to_b256(prevrandao));
+        }
+        if let Some(basefee) = &self.basefee {
+            block.basefee = uint(basefee);
+        }
+        if let Some(gas_limit) = &self.gas_limit {
+            block.gas_limit = uint(gas_limit);
+        }
+    }
+}
+
+impl From<&BlockEnv> for DumpBlock {
+    fn from(block: &BlockEnv) -> Self {
+        let uint = |value: rU256| Some(PermissiveUint(ru256_to_u256(value)));
+        DumpBlock {
+            number: uint(block.number),
+            coinbase: Some(b160_to_h160(block.coinbase)),
+            timestamp: uint(block.timestamp),
+            difficulty: uint(block.difficulty),
+            prevrandao: block.prevrandao.map(b256_to_h256),
+            basefee: uint(block.basefee),
+            gas_limit: uint(block.gas_limit),
+        }
+    }
+}
+
+/// The parts of a geth genesis file which make up the state and the block environment
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Genesis {
+    alloc: BTreeMap<Address, DumpAccount>,
+    number: Option<PermissiveUint>,
+    coinbase: Option<Address>,
+    timestamp: Option<PermissiveUint>,
+    difficulty: Option<PermissiveUint>,
+    mix_hash: Option<Hash>,
+    base_fee_per_gas: Option<PermissiveUint>,
+    gas_limit: Option<PermissiveUint>,
+}
+
+impl From<Genesis> for StateDump {
+    fn from(genesis: Genesis) -> Self {
+        StateDump {
+            block: Some(DumpBlock {
+                number: genesis.number,
+                coinbase: genesis.coinbase,
+                timestamp: genesis.timestamp,
+                difficulty: genesis.difficulty,
+                prevrandao: genesis.mix_hash,
+                basefee: genesis.base_fee_per_gas,
+                gas_limit: genesis.gas_limit,
+            }),
+            accounts: genesis.alloc,
+        }
+    }
+}
+
+impl StateDump {
+    /// Parses an anvil state dump, or a geth genesis file if there is an `alloc`
+    pub fn parse(contents: &str) -> eyre::Result<Self> {
+        let value: Value = serde_json::from_str(contents)?;
+        if value.get("alloc").is_some() {
+            Ok(serde_json::from_value::<Genesis>(value)?.into())
+        } else {
+            Ok(serde_json::from_value(value)?)
+        }
+    }
+
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read state file {}", path.display()))?;
+        Self::parse(&contents).wrap_err_with(|| format!("invalid state file {}", path.display()))
+    }
+
+    /// The accounts as changes to commit to a backend. Storage slots which aren't in the dump
+    /// keep their current value, unless the account's storage was cleared.
+    pub fn changes(&self) -> HashMap<B160, Account> {
+        self.accounts
+            .iter()
+            .map(|(address, dumped)| {
+                let mut account = Account {
+                    info: AccountInfo::default(),
+                    is_destroyed: dumped.destroyed,
+                    storage_cleared: dumped.storage_cleared,
+                    ..Account::new_not_existing()
+                };
+                if dumped.destroyed {
+                    return (h160_to_b160(*address), account);
+                }
+                if let Some(balance) = dumped.balance {
+                    account.info.balance = u256_to_ru256(balance.0);
+                }
+                if let Some(nonce) = dumped.nonce {
+                    account.info.nonce = nonce;
+                }
+                if let Some(code) = dumped.code.as_ref().filter(|code| !code.is_empty()) {
+                    account.info.code = Some(Bytecode::new_raw(code.to_vec().into()));
+                }
+                account
+                    .storage
+                    .extend(dumped.storage.iter().map(|(slot, value)| {
+                        (
+                            u256_to_ru256(slot.0),
+                            StorageSlot::new(u256_to_ru256(value.0)),
+                        )
+                    }));
+
+                (h160_to_b160(*address), account)
+            })
+            .collect()
+    }
+}
+
+/// The accounts which have been changed in a database rather than only read, with their code and
+/// every storage slot the database holds for them. Self-destructed accounts are included as
+/// destroyed.
+pub fn changed_accounts<ExtDB>(db: &CacheDB<ExtDB>) -> BTreeMap<Address, DumpAccount> {
+    db.accounts
+        .iter()
+        .filter(|(_, account)| {
+            matches!(
+                account.account_state,
+                AccountState::Touched | AccountState::StorageCleared | AccountState::NotExisting
+            )
+        })
+        .map(|(address, account)| {
+            // Accounts the database has never seen are read from the forked chain rather than
+            // cached as not existing, so these self-destructed
+            if matches!(account.account_state, AccountState::NotExisting) {
+                let destroyed = DumpAccount {
+                    destroyed: true,
+                    ..Default::default()
+                };
+                return (b160_to_h160(*address), destroyed);
+            }
+
+            let code = account
+                .info
+                .code
+                .as_ref()
+                .or_else(|| db.contracts.get(&account.info.code_hash))
+                .map(|code| Bytes(code.original_bytes()))
+                .filter(|code| !code.is_empty());
+            let storage = account
+                .storage
+                .iter()
+                .map(|(slot, value)| {
+                    (
+                        PermissiveUint(ru256_to_u256(*slot)),
+                        PermissiveUint(ru256_to_u256(*value)),
+                    )
+                })
+                .collect();
+
+            (
+                b160_to_h160(*address),
+                DumpAccount {
+                    nonce: Some(account.info.nonce),
+                    balance: Some(PermissiveUint(ru256_to_u256(account.info.balance))),
+                    code,
+                    storage,
+                    destroyed: false,
+                    storage_cleared: matches!(account.account_state, AccountState::StorageCleared),
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::Uint;
+    use revm::db::EmptyDB;
+    use revm::DatabaseCommit;
+
+    use super::*;
+
+    const ACCOUNT: &str = "0x000000000000000000000000000000000000c0de";
+
+    #[test]
+    fn test_parse_anvil_dump() {
+        let dump = StateDump::parse(
+            r#"{
+                "block": {
+                    "number": "0x10",
+                    "coinbase": "0x0000000000000000000000000000000000000000",
+                    "timestamp": "0x6553f100",
+                    "gas_limit": "0x1c9c380",
+                    "basefee": "0x0",
+                    "difficulty": "0x0",
+                    "prevrandao": "0x0000000000000000000000000000000000000000000000000000000000000000"
+                },
+                "accounts": {
+                    "0x000000000000000000000000000000000000c0de": {
+                        "nonce": 1,
+                        "balance": "0xde0b6b3a7640000",
+                        "code": "0x6000",
+                        "storage": { "0x0": "0x5" }
+                    }
+                },
+                "best_block_number": "0x10"
+            }"#,
+        )
+        .unwrap();
+
+        let block = dump.block.clone().unwrap();
+        assert_eq!(block.number, Some(PermissiveUint(Uint::from(16))));
+        assert_eq!(block.gas_limit, Some(PermissiveUint(Uint::from(30000000))));
+
+        let account = &dump.accounts[&ACCOUNT.parse().unwrap()];
+        assert_eq!(account.nonce, Some(1));
+        assert_eq!(
+            account.storage[&PermissiveUint(Uint::zero())],
+            PermissiveUint(Uint::from(5))
+        );
+
+        let changes = dump.changes();
+        let account = &changes[&h160_to_b160(ACCOUNT.parse().unwrap())];
+        assert_eq!(account.info.nonce, 1);
+        assert_eq!(
+            account.info.balance,
+            rU256::from(1_000_000_000_000_000_000u64)
+        );
+        assert!(account.info.code.is_some());
+        assert_eq!(
+            account.storage[&rU256::ZERO].present_value(),
+            rU256::from(5)
+        );
+    }
+
+    #[test]
+    fn test_parse_genesis() {
+        let dump = StateDump::parse(
+            r#"{
+                "config": { "chainId": 1337 },
+                "timestamp": "0x0",
+                "gasLimit": "0x1c9c380",
+                "difficulty": "1",
+                "baseFeePerGas": "1000000000",
+                "alloc": {
+                    "000000000000000000000000000000000000c0de": { "balance": "1000" },
+                    "0x00000000000000000000000000000000000000aa": {
+                        "balance": "0x0",
+                        "nonce": "0x2",
+                        "code": "0x6000",
+                        "storage": {
+                            "0x0000000000000000000000000000000000000000000000000000000000000001": "0x0000000000000000000000000000000000000000000000000000000000000002"
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let block = dump.block.unwrap();
+        assert_eq!(block.gas_limit, Some(PermissiveUint(Uint::from(30000000))));
+        assert_eq!(block.basefee, Some(PermissiveUint(Uint::from(1000000000))));
+        assert_eq!(block.difficulty, Some(PermissiveUint(Uint::one())));
+
+        assert_eq!(
+            dump.accounts[&ACCOUNT.parse().unwrap()].balance,
+            Some(PermissiveUint(Uint::from(1000)))
+        );
+        let contract = &dump.accounts[&"0x00000000000000000000000000000000000000aa"
+            .parse()
+            .unwrap()];
+        assert_eq!(contract.nonce, Some(2));
+        assert_eq!(
+            contract.storage[&PermissiveUint(Uint::one())],
+            PermissiveUint(Uint::from(2))
+        );
+    }
+
+    #[test]
+    fn test_destroyed_and_cleared_accounts() {
+        let dump = StateDump::parse(
+            r#"{
+                "accounts": {
+                    "0x000000000000000000000000000000000000c0de": { "destroyed": true },
+                    "0x00000000000000000000000000000000000000aa": {
+                        "nonce": 1,
+                        "balance": "0x0",
+                        "storage": { "0x1": "0x2" },
+                        "storage_cleared": true
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        let destroyed = h160_to_b160(ACCOUNT.parse().unwrap());
+        let cleared = B160::from_low_u64_be(0xaa);
+        for address in [destroyed, cleared] {
+            db.insert_account_info(
+                address,
+                AccountInfo {
+                    balance: rU256::from(1),
+                    ..Default::default()
+                },
+            );
+            db.insert_account_storage(address, rU256::from(3), rU256::from(4))
+                .unwrap();
+        }
+        db.commit(dump.changes());
+
+        assert!(matches!(
+            db.accounts[&destroyed].account_state,
+            AccountState::NotExisting
+        ));
+        assert!(matches!(
+            db.accounts[&cleared].account_state,
+            AccountState::StorageCleared
+        ));
+        assert!(!db.accounts[&cleared].storage.contains_key(&rU256::from(3)));
+        assert_eq!(changed_accounts(&db), dump.accounts);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(StateDump::parse("[]").is_err());
+        assert!(StateDump::parse(r#"{ "alloc": { "0x00": {} } }"#).is_err());
+        assert!(StateDump::parse(r#"{ "accounts": { "0x000000000000000000000000000000000000c0de": { "balance": "-1" } } }"#).is_err());
+    }
+
+    #[test]
+    fn test_changed_accounts() {
+        let dump = StateDump::parse(
+            r#"{
+                "accounts": {
+                    "0x000000000000000000000000000000000000c0de": {
+                        "nonce": 1,
+                        "balance": "0x10",
+                        "code": "0x6000",
+                        "storage": { "0x1": "0x2" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.commit(dump.changes());
+        // Accounts read from the forked chain are cached without being changed
+        db.insert_account_info(B160::from_low_u64_be(1), AccountInfo::default());
+
+        assert_eq!(changed_accounts(&db), dump.accounts);
+        assert_eq!(
+            DumpBlock::from(&BlockEnv::default()).number,
+            Some(PermissiveUint(Uint::zero()))
+        );
+    }
+}
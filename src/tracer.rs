@@ -0,0 +1,555 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+
+use ethers::abi::{Address, Hash, Uint};
+use ethers::types::{Bytes, U64};
+use ethers::utils::hex;
+use foundry_evm::trace::{
+    CallTraceArena, RawOrDecodedCall, RawOrDecodedLog, RawOrDecodedReturnData,
+};
+use foundry_evm::utils::{b160_to_h160, ru256_to_u256};
+use foundry_evm::CallKind;
+use revm::db::DatabaseRef;
+use revm::interpreter::{opcode, CallInputs, CreateInputs, Gas, InstructionResult, Interpreter};
+use revm::primitives::{AccountInfo, Bytes as rBytes, State, B160, U256 as rU256};
+use revm::{Database, EVMData, Inspector};
+use serde::{Deserialize, Serialize};
+
+use crate::revert::RevertReason;
+
+/// Options of the tracers, named like the trace config of `debug_traceCall`. Without a `tracer`,
+/// the opcode tracer logs every step of execution.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceConfig {
+    pub tracer: Option<GethTracer>,
+    #[serde(default)]
+    pub tracer_config: TracerConfig,
+    #[serde(default)]
+    pub enable_memory: bool,
+    #[serde(default)]
+    pub disable_stack: bool,
+    #[serde(default)]
+    pub disable_storage: bool,
+    /// The maximum number of steps to log, unlimited if zero
+    #[serde(default)]
+    pub limit: usize,
+    /// The maximum number of bytes of memory to log at each step, unlimited if zero
+    #[serde(default)]
+    pub memory_limit: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum GethTracer {
+    #[serde(rename = "callTracer")]
+    CallTracer,
+    #[serde(rename = "prestateTracer")]
+    PrestateTracer,
+    #[serde(rename = "4byteTracer")]
+    FourByteTracer,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TracerConfig {
+    /// Only trace the transaction's own call, with the `callTracer`
+    #[serde(default)]
+    pub only_top_call: bool,
+    /// Include the logs emitted by each call, with the `callTracer`
+    #[serde(default)]
+    pub with_log: bool,
+    /// Return the state before and after the transaction, with the `prestateTracer`
+    #[serde(default)]
+    pub diff_mode: bool,
+}
+
+/// The output of a named tracer, in the shape geth returns it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum GethTrace {
+    CallTracer(CallFrame),
+    PrestateDiff(PrestateDiff),
+    Prestate(BTreeMap<Address, AccountState>),
+    /// The number of calls per selector and calldata size, keyed like `0x095ea7b3-64`
+    FourByteTracer(BTreeMap<String, u64>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallFrame {
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub from: Address,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Uint>,
+    pub gas: U64,
+    pub gas_used: U64,
+    pub input: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub calls: Vec<CallFrame>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logs: Vec<CallLog>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CallLog {
+    pub address: Address,
+    pub topics: Vec<Hash>,
+    pub data: Bytes,
+}
+
+/// An account as seen by the `prestateTracer`. Empty fields are omitted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct AccountState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Uint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage: BTreeMap<Hash, Hash>,
+}
+
+/// The accounts changed by a transaction, before and after. `post` only has the fields which
+/// changed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PrestateDiff {
+    pub pre: BTreeMap<Address, AccountState>,
+    pub post: BTreeMap<Address, AccountState>,
+}
+
+/// Builds the output of the `callTracer` from a trace. The transaction's own frame reports its
+/// gas limit and the gas used by the whole transaction, like geth.
+pub fn call_frame(
+    arena: &CallTraceArena,
+    gas_limits: &[u64],
+    config: &TracerConfig,
+    gas_limit: u64,
+    gas_used: u64,
+) -> CallFrame {
+    let mut frame = node_frame(arena, 0, gas_limits, config);
+    frame.gas = U64::from(gas_limit);
+    frame.gas_used = U64::from(gas_used);
+    frame
+}
+
+fn node_frame(
+    arena: &CallTraceArena,
+    idx: usize,
+    gas_limits: &[u64],
+    config: &TracerConfig,
+) -> CallFrame {
+    let node = &arena.arena[idx];
+    let call = &node.trace;
+    let is_create = matches!(call.kind, CallKind::Create | CallKind::Create2);
+
+    let output = match &call.output {
+        RawOrDecodedReturnData::Raw(output) if !output.is_empty() => Some(output.clone()),
+        _ => None,
+    };
+    let error = match call.status {
+        InstructionResult::Continue
+        | InstructionResult::Stop
+        | InstructionResult::Return
+        | InstructionResult::SelfDestruct => None,
+        InstructionResult::Revert => Some("execution reverted".to_string()),
+        InstructionResult::OutOfGas
+        | InstructionResult::MemoryOOG
+        | InstructionResult::PrecompileOOG
+        | InstructionResult::InvalidOperandOOG => Some("out of gas".to_string()),
+        status => Some(format!("{status:?}")),
+    };
+    let revert_reason = match (call.status, &output) {
+        (InstructionResult::Revert, Some(output)) => match RevertReason::decode(output) {
+            Some(RevertReason::Error { message }) => Some(message),
+            Some(RevertReason::Panic { reason, .. }) => Some(reason),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    CallFrame {
+        call_type: match call.kind {
+            CallKind::Call => "CALL",
+            CallKind::StaticCall => "STATICCALL",
+            CallKind::CallCode => "CALLCODE",
+            CallKind::DelegateCall => "DELEGATECALL",
+            CallKind::Create => "CREATE",
+            CallKind::Create2 => "CREATE2",
+        }
+        .to_string(),
+        from: call.caller,
+        // A failed create has no address
+        to: (!is_create || call.success).then_some(call.address),
+        value: (call.kind != CallKind::StaticCall).then_some(call.value),
+        gas: U64::from(gas_limits.get(idx).copied().unwrap_or(call.gas_cost)),
+        gas_used: U64::from(call.gas_cost),
+        input: match &call.data {
+            RawOrDecodedCall::Raw(data) => data.clone(),
+            RawOrDecodedCall::Decoded(..) => Bytes::default(),
+        },
+        // geth leaves out the code returned by creates
+        output: output.filter(|_| !is_create),
+        error,
+        revert_reason,
+        calls: if config.only_top_call {
+            vec![]
+        } else {
+            node.children
+                .iter()
+                .map(|child| node_frame(arena, *child, gas_limits, config))
+                .collect()
+        },
+        logs: if config.with_log {
+            node.logs
+                .iter()
+                .filter_map(|log| match log {
+                    RawOrDecodedLog::Raw(log) => Some(CallLog {
+                        address: call.address,
+                        topics: log.topics.clone(),
+                        data: Bytes::from(log.data.clone()),
+                    }),
+                    RawOrDecodedLog::Decoded(..) => None,
+                })
+                .collect()
+        } else {
+            vec![]
+        },
+    }
+}
+
+/// Counts the calls made to each selector with each calldata size, like the `4byteTracer`
+pub fn four_bytes(arena: &CallTraceArena) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for node in &arena.arena {
+        let call = &node.trace;
+        let RawOrDecodedCall::Raw(data) = &call.data else {
+            continue;
+        };
+        if data.len() < 4
+            || matches!(call.kind, CallKind::Create | CallKind::Create2)
+            || is_precompile(call.address)
+        {
+            continue;
+        }
+        let key = format!("0x{}-{}", hex::encode(&data[..4]), data.len() - 4);
+        *counts.entry(key).or_default() += 1;
+    }
+    counts
+}
+
+/// Builds the output of the `prestateTracer` from the accounts a transaction touched, whose state
+/// before it is read from `db`. Like `state_diff`, this has to happen before the changeset is
+/// committed.
+pub fn prestate<DB: DatabaseRef>(db: &DB, changeset: &State, diff_mode: bool) -> GethTrace {
+    let mut pre = BTreeMap::new();
+    let mut post = BTreeMap::new();
+
+    for (address, account) in changeset {
+        let address_h160 = b160_to_h160(*address);
+        if is_precompile(address_h160) {
+            continue;
+        }
+        let before = db.basic(*address).ok().flatten();
+        let before_code = before.as_ref().and_then(|info| code(db, info));
+
+        if !diff_mode {
+            let info = before.unwrap_or_default();
+            pre.insert(
+                address_h160,
+                AccountState {
+                    balance: Some(ru256_to_u256(info.balance)),
+                    nonce: (info.nonce != 0).then_some(info.nonce),
+                    code: before_code,
+                    storage: account
+                        .storage
+                        .iter()
+                        .map(|(slot, value)| (hash(*slot), hash(value.original_value)))
+                        .collect(),
+                },
+            );
+            continue;
+        }
+
+        let changed_storage: Vec<_> = account
+            .storage
+            .iter()
+            .filter(|(_, value)| value.original_value != value.present_value)
+            .collect();
+        let after_code = code(db, &account.info);
+        let before_info = before.clone().unwrap_or_default();
+        let changed = AccountState {
+            balance: (before_info.balance != account.info.balance)
+                .then(|| ru256_to_u256(account.info.balance)),
+            nonce: (before_info.nonce != account.info.nonce).then_some(account.info.nonce),
+            code: (after_code != before_code).then_some(after_code).flatten(),
+            storage: changed_storage
+                .iter()
+                .map(|(slot, value)| (hash(**slot), hash(value.present_value)))
+                .collect(),
+        };
+        if changed == AccountState::default() {
+            continue;
+        }
+
+        // Accounts which didn't exist before the transaction are only in `post`
+        if let Some(info) = &before {
+            pre.insert(
+                address_h160,
+                AccountState {
+                    balance: Some(ru256_to_u256(info.balance)),
+                    nonce: (info.nonce != 0).then_some(info.nonce),
+                    code: before_code,
+                    storage: changed_storage
+                        .iter()
+                        .map(|(slot, value)| (hash(**slot), hash(value.original_value)))
+                        .collect(),
+                },
+            );
+        }
+        post.insert(address_h160, changed);
+    }
+
+    if diff_mode {
+        GethTrace::PrestateDiff(PrestateDiff { pre, post })
+    } else {
+        GethTrace::Prestate(pre)
+    }
+}
+
+/// The code of an account, if it has any
+fn code<DB: DatabaseRef>(db: &DB, info: &AccountInfo) -> Option<Bytes> {
+    let code = match &info.code {
+        Some(code) => code.clone(),
+        None => db.code_by_hash(info.code_hash).ok()?,
+    };
+    let code = code.original_bytes();
+    (!code.is_empty()).then_some(Bytes(code))
+}
+
+fn hash(value: rU256) -> Hash {
+    Hash::from(value.to_be_bytes::<32>())
+}
+
+/// The addresses of the precompiles, which are always warm
+const PRECOMPILES: RangeInclusive<u64> = 1..=9;
+
+pub(crate) fn is_precompile(address: Address) -> bool {
+    PRECOMPILES
+        .clone()
+        .any(|precompile| address == Address::from_low_u64_be(precompile))
+}
+
+pub(crate) fn precompiles() -> Vec<Address> {
+    PRECOMPILES.map(Address::from_low_u64_be).collect()
+}
+
+/// Records the gas given to each call and create, in the order they're made. This is also the
+/// order of the nodes of the `CallTraceArena` of the same execution.
+#[derive(Debug, Default)]
+pub struct CallGasTracer {
+    pub gas_limits: Vec<u64>,
+}
+
+impl<DB: Database> Inspector<DB> for CallGasTracer {
+    fn call(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        inputs: &mut CallInputs,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, rBytes) {
+        self.gas_limits.push(inputs.gas_limit);
+        (InstructionResult::Continue, Gas::new(0), rBytes::new())
+    }
+
+    fn create(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        inputs: &mut CreateInputs,
+    ) -> (InstructionResult, Option<B160>, Gas, rBytes) {
+        self.gas_limits.push(inputs.gas_limit);
+        (
+            InstructionResult::Continue,
+            None,
+            Gas::new(0),
+            rBytes::new(),
+        )
+    }
+}
+
+/// A step of execution, in the shape of geth's `structLogs`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StructLog {
+    pub pc: u64,
+    pub op: String,
+    pub gas: u64,
+    pub gas_cost: u64,
+    pub depth: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<Vec<Uint>>,
+    /// Memory as 32 byte words, without a `0x` prefix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Vec<String>>,
+    /// The storage slots of the current contract read or written so far, only included on
+    /// `SLOAD` and `SSTORE` steps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct PendingStep {
+    idx: usize,
+    op: u8,
+    gas: u64,
+    /// The slot read by an `SLOAD`, whose value is only on the stack after the step
+    slot: Option<rU256>,
+}
+
+/// Records a `StructLog` for every step of execution, up to the configured limit
+#[derive(Debug, Default)]
+pub struct StructLogTracer {
+    config: TraceConfig,
+    logs: Vec<StructLog>,
+    /// The steps which haven't ended yet, `None` for those past the limit. Calls and creates
+    /// only end after the steps of the frame they open.
+    pending: Vec<Option<PendingStep>>,
+    storage: HashMap<B160, BTreeMap<String, String>>,
+}
+
+impl StructLogTracer {
+    pub fn new(config: TraceConfig) -> Self {
+        StructLogTracer {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn into_logs(self) -> Vec<StructLog> {
+        self.logs
+    }
+
+    fn is_full(&self) -> bool {
+        self.config.limit != 0 && self.logs.len() >= self.config.limit
+    }
+
+    fn memory(&self, interp: &Interpreter) -> Vec<String> {
+        let memory = interp.memory.data();
+        let memory = match self.config.memory_limit {
+            0 => &memory[..],
+            limit => &memory[..memory.len().min(limit)],
+        };
+        memory.chunks(32).map(hex::encode).collect()
+    }
+
+    /// Records a storage slot of the current contract, returning a snapshot of the slots
+    /// recorded so far
+    fn record_storage(
+        &mut self,
+        address: B160,
+        slot: rU256,
+        value: rU256,
+    ) -> BTreeMap<String, String> {
+        let storage = self.storage.entry(address).or_default();
+        storage.insert(word(slot), word(value));
+        storage.clone()
+    }
+}
+
+impl<DB: Database> Inspector<DB> for StructLogTracer {
+    fn step(
+        &mut self,
+        interp: &mut Interpreter,
+        data: &mut EVMData<'_, DB>,
+        _is_static: bool,
+    ) -> InstructionResult {
+        if self.is_full() {
+            self.pending.push(None);
+            return InstructionResult::Continue;
+        }
+
+        let op = interp.current_opcode();
+        let stack = interp.stack.data();
+        let storage = match (self.config.disable_storage, op, stack.len()) {
+            (false, opcode::SSTORE, len) if len >= 2 => {
+                Some(self.record_storage(interp.contract.address, stack[len - 1], stack[len - 2]))
+            }
+            _ => None,
+        };
+
+        let gas = interp.gas.remaining();
+        self.pending.push(Some(PendingStep {
+            idx: self.logs.len(),
+            op,
+            gas,
+            slot: (!self.config.disable_storage && op == opcode::SLOAD)
+                .then(|| stack.last().copied())
+                .flatten(),
+        }));
+        self.logs.push(StructLog {
+            pc: interp.program_counter() as u64,
+            op: opcode::OPCODE_JUMPMAP[op as usize]
+                .map(String::from)
+                .unwrap_or_else(|| format!("opcode {op:#04x} not defined")),
+            gas,
+            gas_cost: 0,
+            depth: data.journaled_state.depth(),
+            stack: (!self.config.disable_stack)
+                .then(|| stack.iter().copied().map(ru256_to_u256).collect()),
+            memory: self.config.enable_memory.then(|| self.memory(interp)),
+            storage,
+            error: None,
+        });
+
+        InstructionResult::Continue
+    }
+
+    fn step_end(
+        &mut self,
+        interp: &mut Interpreter,
+        _data: &mut EVMData<'_, DB>,
+        _is_static: bool,
+        eval: InstructionResult,
+    ) -> InstructionResult {
+        let Some(Some(step)) = self.pending.pop() else {
+            return InstructionResult::Continue;
+        };
+
+        let log = &mut self.logs[step.idx];
+        // For calls and creates this includes the gas used by the frame they opened
+        log.gas_cost = step.gas.saturating_sub(interp.gas.remaining());
+        if !matches!(
+            eval,
+            InstructionResult::Continue
+                | InstructionResult::Stop
+                | InstructionResult::Return
+                | InstructionResult::SelfDestruct
+        ) {
+            log.error = Some(format!("{eval:?}"));
+        }
+
+        if let (opcode::SLOAD, Some(slot), Some(value)) =
+            (step.op, step.slot, interp.stack.data().last())
+        {
+            let storage = self.record_storage(interp.contract.address, slot, *value);
+            self.logs[step.idx].storage = Some(storage);
+        }
+
+        InstructionResult::Continue
+    }
+}
+
+fn word(value: rU256) -> String {
+    hex::encode(value.to_be_bytes::<32>())
+}
@@ -1,17 +1,34 @@
-use std::{fs::File, sync::Arc};
+use std::{fs::File, sync::Arc, time::Duration};
 
 use dashmap::DashMap;
 use enso_temper::{
+    assets::AssetStandard,
+    chains::{ChainConfig, LocalChain},
     config::{config, Config},
     errors::{handle_rejection, ErrorMessage},
+    fork::ForkPool,
+    fork_cache::{ForkCache, ForkCachePurgeResponse, ForkCacheStats},
+    provider::{EndpointStats, ProviderPool},
+    replay::ReplayBlockResponse,
+    revert::RevertReason,
+    rpc::{JsonRpcResponse, EXECUTION_REVERTED, METHOD_NOT_FOUND, PARSE_ERROR},
+    session::{remove_idle_sessions, SessionSlots},
     simulate_routes,
     simulation::{
-        SimulationRequest, SimulationResponse, StatefulSimulationEndResponse,
-        StatefulSimulationResponse,
+        AccessListResponse, CodeResponse, GasEstimateResponse, SimulatedBlock, SimulationRequest,
+        SimulationResponse, StatefulSimulationEndResponse, StatefulSimulationInfo,
+        StatefulSimulationResponse, StatefulSimulationSnapshotResponse,
     },
+    state_dump::StateDump,
+    tracer::GethTrace,
     SharedSimulationState,
 };
-use ethers::types::U256;
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::{
+    transaction::eip2718::TypedTransaction, Address, Block, Bytes, Eip1559TransactionRequest,
+    Transaction, TransactionReceipt, H256, U256, U64,
+};
+use serde_json::Value;
 use warp::Filter;
 
 fn filter(
@@ -19,6 +36,9 @@ fn filter(
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = std::convert::Infallible> + Clone {
     let shared_state: Arc<SharedSimulationState> = Arc::new(SharedSimulationState {
         evms: Arc::new(DashMap::new()),
+        forks: Arc::new(ForkPool::new(config.fork_pool_size)),
+        providers: Arc::new(ProviderPool::new(None)),
+        session_slots: SessionSlots::default(),
     });
 
     warp::any()
@@ -26,6 +46,147 @@ fn filter(
         .recover(handle_rejection)
 }
 
+const STAND_IN_CHAIN_ID: u64 = 31337;
+const STAND_IN_BLOCK: u64 = 100;
+/// Returns its storage slot 0 incremented by one, and stores it back
+const COUNTER_CODE: &str = "0x6000546001018060005560005260206000f3";
+
+fn counter_address() -> Address {
+    "0x000000000000000000000000000000000000c0de"
+        .parse()
+        .unwrap()
+}
+
+/// The transactions of the stand-in node's only non-empty block, which each increment the counter
+fn stand_in_transactions() -> Vec<Transaction> {
+    (0..2u64)
+        .map(|index| Transaction {
+            hash: H256::from_low_u64_be(index + 1),
+            nonce: U256::from(index),
+            block_number: Some(U64::from(STAND_IN_BLOCK)),
+            transaction_index: Some(U64::from(index)),
+            from: "0x93621dca56fe26cdee86e4f6b18e116e9758ff11"
+                .parse()
+                .unwrap(),
+            to: Some(counter_address()),
+            gas: U256::from(100000),
+            gas_price: Some(U256::zero()),
+            chain_id: Some(U256::from(STAND_IN_CHAIN_ID)),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// The gas used by the stand-in transactions: the first sets the counter's slot, the second
+/// changes it
+const STAND_IN_GAS_USED: [u64; 2] = [43130, 26030];
+
+/// Answers the JSON-RPC requests made while forking and replaying, from a chain which only holds
+/// the counter contract and the stand-in transactions
+fn stand_in_response(method: &str, params: &[Value]) -> Value {
+    let transactions = stand_in_transactions();
+    match method {
+        "eth_chainId" => serde_json::json!(U64::from(STAND_IN_CHAIN_ID)),
+        "net_version" => serde_json::json!(STAND_IN_CHAIN_ID.to_string()),
+        "eth_blockNumber" => serde_json::json!(U64::from(STAND_IN_BLOCK)),
+        "eth_gasPrice" | "eth_getBalance" | "eth_getTransactionCount" => {
+            serde_json::json!(U256::zero())
+        }
+        "eth_getStorageAt" => serde_json::json!(H256::zero()),
+        "eth_getCode" => {
+            let address: Address = serde_json::from_value(params[0].clone()).unwrap();
+            if address == counter_address() {
+                serde_json::json!(COUNTER_CODE)
+            } else {
+                serde_json::json!("0x")
+            }
+        }
+        "eth_getTransactionByHash" => {
+            let hash: H256 = serde_json::from_value(params[0].clone()).unwrap();
+            serde_json::json!(transactions.into_iter().find(|tx| tx.hash == hash))
+        }
+        // Without `eth_getBlockReceipts`, so receipts are fetched one by one
+        "eth_getTransactionReceipt" => {
+            let hash: H256 = serde_json::from_value(params[0].clone()).unwrap();
+            let receipt = transactions
+                .into_iter()
+                .zip(STAND_IN_GAS_USED)
+                .find(|(tx, _)| tx.hash == hash)
+                .map(|(tx, gas_used)| TransactionReceipt {
+                    transaction_hash: tx.hash,
+                    transaction_index: tx.transaction_index.unwrap(),
+                    block_number: tx.block_number,
+                    from: tx.from,
+                    to: tx.to,
+                    gas_used: Some(U256::from(gas_used)),
+                    status: Some(U64::one()),
+                    ..Default::default()
+                });
+            serde_json::json!(receipt)
+        }
+        "eth_getBlockByNumber" => {
+            let number: U64 = serde_json::from_value(params[0].clone()).unwrap();
+            let full = params[1].as_bool().unwrap_or_default();
+            let block = Block {
+                hash: Some(H256::from_low_u64_be(number.as_u64() << 8)),
+                parent_hash: H256::from_low_u64_be((number.as_u64() - 1) << 8),
+                number: Some(number),
+                author: Some(
+                    "0x00000000000000000000000000000000c0ffee00"
+                        .parse()
+                        .unwrap(),
+                ),
+                timestamp: U256::from(1700000000 + number.as_u64() * 12),
+                gas_limit: U256::from(30000000),
+                base_fee_per_gas: Some(U256::zero()),
+                mix_hash: Some(H256::from_low_u64_be(42)),
+                transactions: if number.as_u64() == STAND_IN_BLOCK {
+                    transactions
+                } else {
+                    vec![]
+                },
+                ..Default::default()
+            };
+            let mut block = serde_json::to_value(block).unwrap();
+            if !full {
+                block["transactions"] = block["transactions"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|tx| tx["hash"].clone())
+                    .collect();
+            }
+            block
+        }
+        _ => Value::Null,
+    }
+}
+
+/// Spawns a local JSON-RPC node serving a small, known history, and returns its URL
+fn stand_in_node() -> String {
+    let node = warp::post().and(warp::body::json()).map(|request: Value| {
+        let params = request["params"].as_array().cloned().unwrap_or_default();
+        let method = request["method"].as_str().unwrap_or_default();
+        warp::reply::json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": stand_in_response(method, &params),
+        }))
+    });
+    let (address, server) = warp::serve(node).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    format!("http://{address}")
+}
+
+/// The config with the stand-in node as the only RPC endpoint of its chain
+fn stand_in_config() -> Config {
+    let mut config = config();
+    config
+        .chains
+        .insert(STAND_IN_CHAIN_ID, ChainConfig::new(stand_in_node()));
+    config
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn post_simulate_file() {
     if config().etherscan_key.is_some() {
@@ -372,32 +533,59 @@ async fn post_simulate_no_data() {
 
 #[tokio::test(flavor = "multi_thread")]
 async fn post_simulate_incorrect_chain_id() {
-    temp_env::async_with_vars([("FORK_URL", Some("https://eth.llamarpc.com"))], async {
-        let filter = filter(config());
+    let mut config = config();
+    config.chains.insert(
+        137,
+        ChainConfig::new("https://eth.llamarpc.com".to_string()),
+    );
+    let filter = filter(config);
 
-        let json = serde_json::json!({
-          "chainId": 137,
-          "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
-          "to": "0x95222290dd7278aa3ddd389cc1e1d165cc4bafe5",
-          "gasLimit": 21000,
-          "value": "100000",
-          "blockNumber": 16784600
-        });
+    let json = serde_json::json!({
+      "chainId": 137,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0x95222290dd7278aa3ddd389cc1e1d165cc4bafe5",
+      "gasLimit": 21000,
+      "value": "100000",
+      "blockNumber": 16784600
+    });
 
-        let res = warp::test::request()
-            .method("POST")
-            .path("/simulate")
-            .json(&json)
-            .reply(&filter)
-            .await;
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INCORRECT_CHAIN_ID".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_unknown_chain_id() {
+    let filter = filter(config());
+
+    let json = serde_json::json!({
+      "chainId": 424242,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0x95222290dd7278aa3ddd389cc1e1d165cc4bafe5",
+      "value": "100000"
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
 
-        assert_eq!(res.status(), 400);
+    assert_eq!(res.status(), 400);
 
-        let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
 
-        assert_eq!(body.message, "INCORRECT_CHAIN_ID".to_string());
-    })
-    .await;
+    assert_eq!(body.message, "CHAIN_ID_NOT_SUPPORTED".to_string());
 }
 
 #[tokio::test(flavor = "multi_thread")]
@@ -809,3 +997,1780 @@ async fn post_simulate_time_sensitive_tx() {
     // when we use the timestamp of the next block.
     assert!(!body.success);
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_reuses_pooled_fork() {
+    let config = config();
+    let shared_state = Arc::new(SharedSimulationState {
+        evms: Arc::new(DashMap::new()),
+        forks: Arc::new(ForkPool::new(config.fork_pool_size)),
+        providers: Arc::new(ProviderPool::new(None)),
+        session_slots: SessionSlots::default(),
+    });
+    let filter = warp::any()
+        .and(simulate_routes(config, shared_state.clone()))
+        .recover(handle_rejection);
+
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0x70a08231000000000000000000000000d8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "gasLimit": 500000,
+      "blockNumber": 16968595,
+    });
+
+    for _ in 0..2 {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/simulate")
+            .json(&json)
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+
+        let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+        assert!(body.success);
+    }
+
+    assert_eq!(shared_state.forks.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_deploy_contract() {
+    let filter = filter(config());
+
+    // Init code which deploys a contract whose runtime code is `PUSH1 0x2a PUSH1 0x00 MSTORE
+    // PUSH1 0x20 PUSH1 0x00 RETURN`, i.e. it always returns 42.
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "data": "0x600a600c600039600a6000f3602a60005260206000f3",
+      "gasLimit": 500000,
+      "blockNumber": 16968595,
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    assert!(body.deployed_address.is_some());
+    assert_eq!(body.deployed_code_size, Some(10));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_call_tree() {
+    let filter = filter(config());
+
+    let file = File::open("tests/body.json").expect("file should open read only");
+    let json: SimulationRequest =
+        serde_json::from_reader(file).expect("file should be proper JSON");
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.trace.len() > 1);
+    assert_eq!(body.trace[0].parent, None);
+    assert_eq!(body.trace[0].depth, 0);
+    assert_eq!(body.trace[0].input, json.data.unwrap_or_default());
+    for (idx, call) in body.trace.iter().enumerate() {
+        for child in &call.children {
+            assert_eq!(body.trace[*child].parent, Some(idx));
+            assert_eq!(body.trace[*child].depth, call.depth + 1);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_revert_reason() {
+    let filter = filter(config());
+
+    // Code which reverts with `Panic(0x11)`, i.e. an arithmetic overflow
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0x1000000000000000000000000000000000000001",
+      "gasLimit": 500000,
+      "blockNumber": 16968595,
+      "stateOverrides": {
+        "0x1000000000000000000000000000000000000001": {
+          "code": "0x634e487b7160e01b600052601160045260246000fd"
+        }
+      }
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(!body.success);
+    assert_eq!(
+        body.revert_reason,
+        Some(RevertReason::Panic {
+            code: U256::from(0x11),
+            reason: "arithmetic underflow or overflow".to_string(),
+        })
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_state_diff() {
+    let filter = filter(config());
+
+    // USDT approve(0x60f727bdead2ce49b00f2a2133fc707b931d130b, 10000000)
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+      "gasLimit": 5000000,
+      "blockNumber": 16968595,
+      "includeStateDiff": true,
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    let state_diff = body.state_diff.expect("state diff should be included");
+    let usdt = state_diff
+        .get(
+            &"0xdac17f958d2ee523a2206206994597c13d831ec7"
+                .parse()
+                .unwrap(),
+        )
+        .expect("USDT storage should have changed");
+    assert_eq!(usdt.storage.len(), 1);
+    let allowance = usdt.storage.values().next().unwrap();
+    assert_eq!(
+        U256::from_big_endian(allowance.to.as_bytes()),
+        U256::from(10000000)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_asset_changes() {
+    let filter = filter(config());
+
+    let json = serde_json::json!([{
+      "chainId": 1,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0x000000000000000000000000000000000000dEaD",
+      "gasLimit": 500000,
+      "value": "1000000000000000000",
+      "blockNumber": 16968595,
+    }, {
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+      "gasLimit": 5000000,
+      "blockNumber": 16968595,
+    }]);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    let balances = &body[0].asset_changes.balances;
+    assert_eq!(balances.len(), 2);
+    assert_eq!(balances[0].standard, AssetStandard::Native);
+    assert_eq!(balances[0].delta.to_string(), "-1000000000000000000");
+    assert_eq!(balances[1].delta.to_string(), "1000000000000000000");
+
+    let approvals = &body[1].asset_changes.approvals;
+    assert_eq!(approvals.len(), 1);
+    assert_eq!(approvals[0].standard, AssetStandard::Erc20);
+    assert_eq!(approvals[0].amount, Some(U256::from(10000000)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_estimate_gas() {
+    let filter = filter(config());
+
+    let mut json = serde_json::json!({
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+      "gasLimit": 5000000,
+      "blockNumber": 16968595,
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/estimate-gas")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: GasEstimateResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.gas_required >= body.gas_used);
+
+    for (gas_limit, success) in [(body.gas_required, true), (body.gas_required - 1, false)] {
+        json["gasLimit"] = gas_limit.into();
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/simulate")
+            .json(&json)
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+
+        let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.success, success);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_create_access_list() {
+    let filter = filter(config());
+
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+      "gasLimit": 5000000,
+      "blockNumber": 16968595,
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/create-access-list")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: AccessListResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    // The recipient is always warm, so it's left out of the access list
+    assert!(body.access_list.0.iter().all(|item| item.address
+        != "0xdac17f958d2ee523a2206206994597c13d831ec7"
+            .parse()
+            .unwrap()));
+    assert!(body.gas_used_with_access_list <= body.gas_used_without_access_list);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_snapshot_revert() {
+    let filter = filter(config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "gasLimit": 5000000,
+            "blockNumber": 16968594,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let id = serde_json::from_slice::<StatefulSimulationResponse>(res.body())
+        .unwrap()
+        .stateful_simulation_id;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}/snapshot").as_str())
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let snapshot: StatefulSimulationSnapshotResponse = serde_json::from_slice(res.body()).unwrap();
+
+    let approve = serde_json::json!([{
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+      "gasLimit": 5000000,
+      "blockNumber": 16968594,
+    }]);
+    let allowance = serde_json::json!([{
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0xdd62ed3e00000000000000000000000093621dca56fe26cdee86e4f6b18e116e9758ff1100000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b",
+      "gasLimit": 5000000,
+      "blockNumber": 16968594,
+    }]);
+
+    let simulate = |json: serde_json::Value| {
+        let filter = filter.clone();
+        async move {
+            let res = warp::test::request()
+                .method("POST")
+                .path(format!("/simulate-stateful/{id}").as_str())
+                .json(&json)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 200);
+            let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+            U256::from_big_endian(&body[0].return_data)
+        }
+    };
+
+    simulate(approve).await;
+    assert_eq!(simulate(allowance.clone()).await, U256::from(10000000));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}/revert/{}", snapshot.snapshot_id).as_str())
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(simulate(allowance).await, U256::zero());
+
+    // Reverting consumes the snapshot
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}/revert/{}", snapshot.snapshot_id).as_str())
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_session_limits() {
+    let config = Config {
+        max_sessions: 1,
+        ..config()
+    };
+    let shared_state = Arc::new(SharedSimulationState {
+        evms: Arc::new(DashMap::new()),
+        forks: Arc::new(ForkPool::new(config.fork_pool_size)),
+        providers: Arc::new(ProviderPool::new(None)),
+        session_slots: SessionSlots::default(),
+    });
+    let filter = warp::any()
+        .and(simulate_routes(config, shared_state.clone()))
+        .recover(handle_rejection);
+
+    let new_simulation_req = serde_json::json!({
+        "chainId": 1,
+        "gasLimit": 5000000,
+        "blockNumber": 16968594,
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&new_simulation_req)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&new_simulation_req)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 429);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body.message, "TOO_MANY_STATEFUL_SIMULATIONS".to_string());
+
+    assert_eq!(
+        remove_idle_sessions(&shared_state.evms, Duration::from_secs(3600)),
+        0
+    );
+    assert_eq!(remove_idle_sessions(&shared_state.evms, Duration::ZERO), 1);
+    assert!(shared_state.evms.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_simulate_stateful_state() {
+    let filter = filter(config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "gasLimit": 5000000,
+            "blockNumber": 16968594,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let id = serde_json::from_slice::<StatefulSimulationResponse>(res.body())
+        .unwrap()
+        .stateful_simulation_id;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}").as_str())
+        .json(&serde_json::json!([{
+          "chainId": 1,
+          "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+          "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+          "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+          "gasLimit": 5000000,
+          "blockNumber": 16968595,
+        }]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(format!("/simulate-stateful/{id}").as_str())
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let info: StatefulSimulationInfo = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(info.stateful_simulation_id, id);
+    assert_eq!(info.chain_id, 1);
+    assert_eq!(info.block_number, 16968595);
+    assert_eq!(info.transaction_count, 1);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/simulate-stateful")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let list: Vec<StatefulSimulationInfo> = serde_json::from_slice(res.body()).unwrap();
+    assert!(list.iter().any(|info| info.stateful_simulation_id == id));
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(
+            format!("/simulate-stateful/{id}/code/0xdac17f958d2ee523a2206206994597c13d831ec7")
+                .as_str(),
+        )
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let code: CodeResponse = serde_json::from_slice(res.body()).unwrap();
+    assert!(!code.code.is_empty());
+
+    // allowance(0x93621dca56fe26cdee86e4f6b18e116e9758ff11, 0x60f727bdead2ce49b00f2a2133fc707b931d130b)
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}/call").as_str())
+        .json(&serde_json::json!({
+          "chainId": 1,
+          "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+          "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+          "data": "0xdd62ed3e00000000000000000000000093621dca56fe26cdee86e4f6b18e116e9758ff1100000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b",
+          "gasLimit": 5000000,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(
+        U256::from_big_endian(&body.return_data),
+        U256::from(10000000)
+    );
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(format!("/simulate-stateful/{id}").as_str())
+        .reply(&filter)
+        .await;
+
+    let info: StatefulSimulationInfo = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(info.transaction_count, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_rpc() {
+    let filter = filter(config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "gasLimit": 5000000,
+            "blockNumber": 16968594,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let id = serde_json::from_slice::<StatefulSimulationResponse>(res.body())
+        .unwrap()
+        .stateful_simulation_id;
+
+    let allowance = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 4,
+        "method": "eth_call",
+        "params": [{
+            "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+            "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+            "data": "0xdd62ed3e00000000000000000000000093621dca56fe26cdee86e4f6b18e116e9758ff1100000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b",
+        }, "latest"],
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}/rpc").as_str())
+        .json(&serde_json::json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": [] },
+            { "jsonrpc": "2.0", "id": 2, "method": "eth_blockNumber", "params": [] },
+            {
+                "jsonrpc": "2.0",
+                "id": 3,
+                "method": "eth_sendTransaction",
+                "params": [{
+                    "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+                    "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+                    "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+                }],
+            },
+            allowance,
+            { "jsonrpc": "2.0", "id": 5, "method": "eth_mine", "params": [] },
+        ]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<JsonRpcResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.len(), 5);
+    assert_eq!(body[0].result, Some(serde_json::json!("0x1")));
+    assert_eq!(body[1].result, Some(serde_json::json!("0x102eb12")));
+    assert!(body[2].result.is_some());
+    // The approval sent earlier in the batch is visible to the call
+    assert_eq!(
+        body[3].result,
+        Some(serde_json::json!(
+            "0x0000000000000000000000000000000000000000000000000000000000989680"
+        ))
+    );
+    assert_eq!(body[4].error.as_ref().unwrap().code, METHOD_NOT_FOUND);
+
+    // USDT requires the allowance to be reset to 0 before changing it, so this reverts
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}/rpc").as_str())
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 6,
+            "method": "eth_call",
+            "params": [{
+                "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+                "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+                "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000000001",
+            }],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: JsonRpcResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.id, serde_json::json!(6));
+    assert_eq!(body.error.unwrap().code, EXECUTION_REVERTED);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_eth_simulate_v1() {
+    let filter = filter(config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/eth-simulate-v1")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "blockNumber": 16968594,
+            "blockStateCalls": [
+                {
+                    "calls": [{
+                        "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+                        "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+                        "input": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+                    }],
+                },
+                {
+                    "blockOverrides": {
+                        "number": "0x102eb20",
+                        "baseFeePerGas": "0x3b9aca00",
+                    },
+                    "calls": [
+                        {
+                            "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+                            "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+                            "input": "0xdd62ed3e00000000000000000000000093621dca56fe26cdee86e4f6b18e116e9758ff1100000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b",
+                        },
+                        // USDT requires the allowance to be reset to 0 before changing it
+                        {
+                            "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+                            "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+                            "input": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000000001",
+                        },
+                    ],
+                },
+            ],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let blocks: Vec<SimulatedBlock> = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].number.as_u64(), 16968595);
+    assert_eq!(blocks[1].number.as_u64(), 16968608);
+    assert_eq!(
+        blocks[1].timestamp.as_u64(),
+        blocks[0].timestamp.as_u64() + 12
+    );
+    assert_eq!(blocks[1].base_fee_per_gas, U256::from(1000000000));
+
+    let approve = &blocks[0].calls[0];
+    assert_eq!(approve.status.as_u64(), 1);
+    assert_eq!(approve.logs.len(), 1);
+    assert_eq!(blocks[0].gas_used, approve.gas_used);
+
+    // The approval from the first block is visible in the second
+    let allowance = &blocks[1].calls[0];
+    assert_eq!(allowance.status.as_u64(), 1);
+    assert_eq!(
+        U256::from_big_endian(&allowance.return_data),
+        U256::from(10000000)
+    );
+    assert_eq!(blocks[1].calls[1].status.as_u64(), 0);
+    assert!(blocks[1].calls[1].error.is_some());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/eth-simulate-v1")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "blockNumber": 16968594,
+            "blockStateCalls": [{ "blockOverrides": { "number": "0x102eb12" } }],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INVALID_BLOCK_NUMBERS");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_block_overrides() {
+    let filter = filter(config());
+
+    // Returns COINBASE, PREVRANDAO, BASEFEE, GASLIMIT and CHAINID
+    let code = "0x416000524460205248604052456060524660805260a06000f3";
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+            "to": "0x000000000000000000000000000000000000beef",
+            "gasLimit": 100000,
+            "blockNumber": 16968595,
+            "blockOverrides": {
+                "feeRecipient": "0x0000000000000000000000000000000000c0ffee",
+                "prevRandao": "0x000000000000000000000000000000000000000000000000000000000000002a",
+                "baseFeePerGas": "0x3b9aca00",
+                "gasLimit": "0x1c9c380",
+                "chainId": "0x5",
+            },
+            "stateOverrides": {
+                "0x000000000000000000000000000000000000beef": { "code": code },
+            },
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    let words: Vec<U256> = body
+        .return_data
+        .chunks(32)
+        .map(U256::from_big_endian)
+        .collect();
+    assert_eq!(
+        words,
+        vec![
+            U256::from(0xc0ffee),
+            U256::from(42),
+            U256::from(1000000000),
+            U256::from(30000000),
+            U256::from(5),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_realistic_fees() {
+    let filter = filter(config());
+
+    let from: Address = "0x93621dca56fe26cdee86e4f6b18e116e9758ff11"
+        .parse()
+        .unwrap();
+    let request = |balance: &str| {
+        serde_json::json!({
+            "chainId": 1,
+            "from": from,
+            "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+            "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+            "gasLimit": 500000,
+            "blockNumber": 16968595,
+            "maxFeePerGas": "100000000000",
+            "maxPriorityFeePerGas": "2000000000",
+            "realisticFees": true,
+            "includeStateDiff": true,
+            "stateOverrides": {
+                "0x93621dca56fe26cdee86e4f6b18e116e9758ff11": { "balance": balance },
+            },
+        })
+    };
+
+    // 500000 gas at 100 gwei is more than the sender has
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&request("1000000000000000"))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INSUFFICIENT_FUNDS_FOR_GAS");
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&request("1000000000000000000"))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    let gas_used = U256::from(body.gas_used);
+    assert_eq!(
+        body.fees.total_fee,
+        body.fees.effective_gas_price * gas_used
+    );
+    assert_eq!(body.fees.coinbase_tip, U256::from(2000000000) * gas_used);
+
+    // The sender is charged the total fee
+    let balance = body.state_diff.unwrap()[&from].balance.clone().unwrap();
+    assert_eq!(balance.from - balance.to, body.fees.total_fee);
+
+    // Without fee fields the transaction pays the base fee
+    let mut json = request("1000000000000000000");
+    let fields = json.as_object_mut().unwrap();
+    fields.remove("maxFeePerGas");
+    fields.remove("maxPriorityFeePerGas");
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    assert!(body.fees.effective_gas_price > U256::zero());
+    assert_eq!(body.fees.coinbase_tip, U256::zero());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_raw() {
+    let filter = filter(config());
+
+    // A new account, so it has no ETH and its nonce is 0
+    let wallet = LocalWallet::new(&mut ethers::core::rand::thread_rng()).with_chain_id(1u64);
+    let sign = |nonce: u64| {
+        let transaction: TypedTransaction = Eip1559TransactionRequest::new()
+            .to("0xdac17f958d2ee523a2206206994597c13d831ec7".parse::<Address>().unwrap())
+            .data("0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680".parse::<Bytes>().unwrap())
+            .gas(100000)
+            .max_fee_per_gas(100000000000u64)
+            .max_priority_fee_per_gas(2000000000u64)
+            .nonce(nonce)
+            .chain_id(1)
+            .into();
+        let signature = wallet.sign_transaction_sync(&transaction).unwrap();
+        transaction.rlp_signed(&signature)
+    };
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-raw")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "transactions": [sign(0), sign(1)],
+            "blockNumber": 16968595,
+            "realisticFees": false,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.len(), 2);
+    assert!(body[0].success);
+    // The sender is recovered from the signature
+    assert_eq!(body[0].trace[0].from, wallet.address());
+    assert_eq!(
+        body[0].fees.coinbase_tip,
+        U256::from(2000000000) * body[0].gas_used
+    );
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-raw")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "transactions": [sign(1)],
+            "blockNumber": 16968595,
+            "realisticFees": false,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "NONCE_TOO_HIGH");
+
+    // Fees are charged by default, which the new account can't pay
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-raw")
+        .json(&serde_json::json!({
+            "chainId": 1,
+            "transactions": [sign(0)],
+            "blockNumber": 16968595,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INSUFFICIENT_FUNDS_FOR_GAS");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay_transaction() {
+    let filter = filter(stand_in_config());
+    let hash = stand_in_transactions()[1].hash;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/replay/{hash:?}"))
+        .json(&serde_json::json!({ "chainId": STAND_IN_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    assert_eq!(body.block_number, STAND_IN_BLOCK);
+    // The first transaction of the block is replayed before it
+    assert_eq!(U256::from_big_endian(&body.return_data), U256::from(2));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/replay/{hash:?}"))
+        .json(&serde_json::json!({
+            "chainId": STAND_IN_CHAIN_ID,
+            "data": "0x1234",
+            "stateOverrides": {
+                format!("{:?}", counter_address()): {
+                    "stateDiff": {
+                        format!("{:?}", H256::zero()): "10"
+                    }
+                }
+            }
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    // Overrides are applied after the preceding transactions
+    assert_eq!(U256::from_big_endian(&body.return_data), U256::from(11));
+    assert_eq!(body.trace[0].input, "0x1234".parse::<Bytes>().unwrap());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/replay/{:?}", H256::from_low_u64_be(3)))
+        .json(&serde_json::json!({ "chainId": STAND_IN_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 404);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "TRANSACTION_NOT_FOUND");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay_block() {
+    let filter = filter(stand_in_config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/replay-block/{STAND_IN_BLOCK}"))
+        .json(&serde_json::json!({ "chainId": STAND_IN_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: ReplayBlockResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.matches);
+    assert_eq!(body.block_number, STAND_IN_BLOCK);
+    assert_eq!(body.transactions.len(), 2);
+    for (index, transaction) in body.transactions.iter().enumerate() {
+        assert_eq!(
+            transaction.transaction_hash,
+            stand_in_transactions()[index].hash
+        );
+        assert_eq!(transaction.simulation.gas_used, STAND_IN_GAS_USED[index]);
+        assert_eq!(transaction.receipt_success, Some(true));
+        assert!(transaction.simulation.success);
+        assert!(!transaction.simulation.trace.is_empty());
+        assert_eq!(
+            U256::from_big_endian(&transaction.simulation.return_data),
+            U256::from(index + 1)
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay_transaction_failover() {
+    // Nothing listens on port 1, so every request to it fails
+    let unreachable = "http://127.0.0.1:1".to_string();
+    let mut config = config();
+    config.chains.insert(
+        STAND_IN_CHAIN_ID,
+        ChainConfig {
+            rpc_urls: vec![unreachable.clone(), stand_in_node()],
+            ..ChainConfig::new(unreachable.clone())
+        },
+    );
+    let filter = filter(config);
+    let hash = stand_in_transactions()[1].hash;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/replay/{hash:?}"))
+        .json(&serde_json::json!({ "chainId": STAND_IN_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(U256::from_big_endian(&body.return_data), U256::from(2));
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/rpc-endpoints")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<EndpointStats> = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.len(), 2);
+    assert_eq!(body[0].url, unreachable);
+    assert!(body[0].errors > 0);
+    // The unreachable endpoint is skipped once it has failed a few times
+    assert!(!body[0].healthy);
+    assert!(body[1].requests > 0);
+    assert_eq!(body[1].errors, 0);
+    assert!(body[1].healthy);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay_transaction_rpc_unavailable() {
+    let mut config = config();
+    config.chains.insert(
+        STAND_IN_CHAIN_ID,
+        ChainConfig::new("http://127.0.0.1:1".to_string()),
+    );
+    let filter = filter(config);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/replay/{:?}", H256::from_low_u64_be(1)))
+        .json(&serde_json::json!({ "chainId": STAND_IN_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 502);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "RPC_ERROR");
+}
+
+const LOCAL_CHAIN_ID: u64 = 1337;
+
+/// The config with a local chain, which only holds the counter contract with its slot set to 5
+fn local_config() -> Config {
+    let genesis = serde_json::json!({
+        "number": "0x7",
+        "timestamp": "1700000000",
+        "alloc": {
+            format!("{:?}", counter_address()): {
+                "balance": "0x0",
+                "code": COUNTER_CODE,
+                "storage": { "0x0": "0x5" }
+            }
+        }
+    });
+    let local = LocalChain {
+        base_fee: Some(0),
+        dump: Arc::new(StateDump::parse(&genesis.to_string()).unwrap()),
+        ..Default::default()
+    };
+
+    let mut config = config();
+    config
+        .chains
+        .insert(LOCAL_CHAIN_ID, ChainConfig::local(local));
+    config
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_local_chain() {
+    let filter = filter(local_config());
+
+    let json = serde_json::json!({
+        "chainId": LOCAL_CHAIN_ID,
+        "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+        "to": counter_address(),
+    });
+
+    // Every simulation starts from the state file, so the counter is incremented from 5 each time
+    for _ in 0..2 {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/simulate")
+            .json(&json)
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+
+        let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+        assert!(body.success);
+        assert_eq!(body.block_number, 7);
+        assert_eq!(
+            body.return_data,
+            Bytes::from(H256::from_low_u64_be(6).as_bytes().to_vec())
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_gas_limit_above_block() {
+    let filter = filter(local_config());
+
+    // The local chain's blocks have the default gas limit of 30M
+    let json = serde_json::json!({
+        "chainId": LOCAL_CHAIN_ID,
+        "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+        "to": counter_address(),
+        "gasLimit": 50000000,
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_eth_simulate_v1_local_chain() {
+    let filter = filter(local_config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/eth-simulate-v1")
+        .json(&serde_json::json!({
+            "chainId": LOCAL_CHAIN_ID,
+            "blockStateCalls": [{
+                "blockOverrides": { "number": "0xa" },
+                "calls": [
+                    // Too little gas to even start the call
+                    { "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11", "to": counter_address(), "gas": "0x1" },
+                    { "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11", "to": counter_address() },
+                ],
+            }],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulatedBlock> = serde_json::from_slice(res.body()).unwrap();
+
+    // Blocks 8 and 9 are skipped, and returned empty
+    assert_eq!(body.len(), 3);
+    assert_eq!(body[0].number, U64::from(8));
+    assert!(body[0].calls.is_empty());
+    assert_eq!(body[1].number, U64::from(9));
+    assert!(body[1].timestamp > body[0].timestamp);
+    assert!(body[2].timestamp > body[1].timestamp);
+
+    let calls = &body[2].calls;
+    assert_eq!(calls[0].status, U64::zero());
+    assert!(calls[0].error.is_some());
+    assert_eq!(calls[1].status, U64::one());
+    assert_eq!(
+        calls[1].return_data,
+        Bytes::from(H256::from_low_u64_be(6).as_bytes().to_vec())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay_transaction_local_chain() {
+    let filter = filter(local_config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/replay/{:?}", H256::from_low_u64_be(1)))
+        .json(&serde_json::json!({ "chainId": LOCAL_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "NOT_SUPPORTED_ON_LOCAL_CHAIN");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_rpc_receipt() {
+    let filter = filter(local_config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({ "chainId": LOCAL_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+    let session: StatefulSimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    let path = format!("/simulate-stateful/{}/rpc", session.stateful_simulation_id);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&path)
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendTransaction",
+            "params": [{
+                "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+                "to": counter_address(),
+            }],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: JsonRpcResponse = serde_json::from_slice(res.body()).unwrap();
+    let hash: H256 = serde_json::from_value(body.result.unwrap()).unwrap();
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&path)
+        .json(&serde_json::json!([
+            { "jsonrpc": "2.0", "id": 2, "method": "eth_getTransactionByHash", "params": [hash] },
+            { "jsonrpc": "2.0", "id": 3, "method": "eth_getTransactionReceipt", "params": [hash] },
+            {
+                "jsonrpc": "2.0",
+                "id": 4,
+                "method": "eth_getTransactionReceipt",
+                "params": [H256::from_low_u64_be(1)],
+            },
+        ]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<JsonRpcResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    let transaction: Transaction = serde_json::from_value(body[0].result.clone().unwrap()).unwrap();
+    assert_eq!(transaction.hash, hash);
+    assert_eq!(transaction.to, Some(counter_address()));
+    assert_eq!(transaction.block_number, Some(U64::from(7)));
+
+    let receipt: TransactionReceipt =
+        serde_json::from_value(body[1].result.clone().unwrap()).unwrap();
+    assert_eq!(receipt.transaction_hash, hash);
+    assert_eq!(receipt.status, Some(U64::one()));
+    assert!(receipt.gas_used.unwrap() > U256::zero());
+
+    // Unknown transactions are `null`
+    assert!(body[2].result.is_none() && body[2].error.is_none());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&path)
+        .body("{ \"jsonrpc\": ")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: JsonRpcResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.error.unwrap().code, PARSE_ERROR);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_rpc_receipt_fees() {
+    let filter = filter(local_config());
+
+    let wallet =
+        LocalWallet::new(&mut ethers::core::rand::thread_rng()).with_chain_id(LOCAL_CHAIN_ID);
+    let sign = |nonce: u64| {
+        let transaction: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(counter_address())
+            .gas(100000)
+            .max_fee_per_gas(10000000000u64)
+            .max_priority_fee_per_gas(1000000000u64)
+            .nonce(nonce)
+            .chain_id(LOCAL_CHAIN_ID)
+            .into();
+        let signature = wallet.sign_transaction_sync(&transaction).unwrap();
+        transaction.rlp_signed(&signature)
+    };
+
+    // The sender needs ETH, as raw transactions are charged realistic fees
+    let state = serde_json::json!({
+        "accounts": {
+            format!("{:?}", wallet.address()): { "balance": "1000000000000000000" }
+        }
+    });
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({ "chainId": LOCAL_CHAIN_ID, "state": state }))
+        .reply(&filter)
+        .await;
+    let session: StatefulSimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    let path = format!("/simulate-stateful/{}/rpc", session.stateful_simulation_id);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&path)
+        .json(&serde_json::json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": [sign(0)] },
+            { "jsonrpc": "2.0", "id": 2, "method": "eth_sendRawTransaction", "params": [sign(1)] },
+        ]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<JsonRpcResponse> = serde_json::from_slice(res.body()).unwrap();
+    let hashes: Vec<H256> = body
+        .into_iter()
+        .map(|response| serde_json::from_value(response.result.unwrap()).unwrap())
+        .collect();
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&path)
+        .json(&serde_json::json!([
+            { "jsonrpc": "2.0", "id": 3, "method": "eth_getTransactionReceipt", "params": [hashes[0]] },
+            { "jsonrpc": "2.0", "id": 4, "method": "eth_getTransactionReceipt", "params": [hashes[1]] },
+        ]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<JsonRpcResponse> = serde_json::from_slice(res.body()).unwrap();
+    let receipts: Vec<TransactionReceipt> = body
+        .into_iter()
+        .map(|response| serde_json::from_value(response.result.unwrap()).unwrap())
+        .collect();
+
+    // The base fee is zero, so only the priority fee is paid rather than the max fee
+    assert_eq!(
+        receipts[0].effective_gas_price,
+        Some(U256::from(1000000000u64))
+    );
+    let gas_used = |receipt: &TransactionReceipt| receipt.gas_used.unwrap();
+    assert_eq!(receipts[0].cumulative_gas_used, gas_used(&receipts[0]));
+    assert_eq!(
+        receipts[1].cumulative_gas_used,
+        gas_used(&receipts[0]) + gas_used(&receipts[1])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_not_found() {
+    let filter = filter(local_config());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful/6f676bc7-3416-4647-99ee-e1be90fb6d2e")
+        .json(&serde_json::json!([{
+            "chainId": LOCAL_CHAIN_ID,
+            "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+            "to": counter_address(),
+        }]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 404);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "STATE_NOT_FOUND");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_simulate_stateful_state_dump() {
+    let filter = filter(local_config());
+    let increment = serde_json::json!([{
+        "chainId": LOCAL_CHAIN_ID,
+        "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+        "to": counter_address(),
+    }]);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({ "chainId": LOCAL_CHAIN_ID }))
+        .reply(&filter)
+        .await;
+    let session: StatefulSimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    let id = session.stateful_simulation_id;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulate-stateful/{id}"))
+        .json(&increment)
+        .reply(&filter)
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/simulate-stateful/{id}/state"))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let dump: StateDump = serde_json::from_slice(res.body()).unwrap();
+    let counter = &dump.accounts[&counter_address()];
+    assert_eq!(counter.code, Some(COUNTER_CODE.parse().unwrap()));
+    assert_eq!(
+        serde_json::to_value(&counter.storage).unwrap(),
+        serde_json::json!({ "0x0": "0x6" })
+    );
+    let block = dump.block.as_ref().unwrap();
+    assert_eq!(serde_json::to_value(block.number).unwrap(), "0x7");
+
+    // A session seeded from the dump continues where the exported one left off
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({ "chainId": LOCAL_CHAIN_ID, "state": dump }))
+        .reply(&filter)
+        .await;
+    assert_eq!(res.status(), 200);
+    let session: StatefulSimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!(
+            "/simulate-stateful/{}",
+            session.stateful_simulation_id
+        ))
+        .json(&increment)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(
+        body[0].return_data,
+        Bytes::from(H256::from_low_u64_be(7).as_bytes().to_vec())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_fork_cache() {
+    let dir = std::env::temp_dir().join("temper-api-fork-cache");
+    let _ = std::fs::remove_dir_all(&dir);
+    // Without pooling every replay spawns a new fork, which fetches its state again
+    let config = Config {
+        fork_pool_size: 0,
+        fork_cache_dir: Some(dir.clone()),
+        ..stand_in_config()
+    };
+    let fork_cache = Arc::new(ForkCache::open(dir.clone(), config.fork_cache_size).unwrap());
+    let shared_state = Arc::new(SharedSimulationState {
+        evms: Arc::new(DashMap::new()),
+        forks: Arc::new(ForkPool::new(config.fork_pool_size).with_rpc_cache(false)),
+        providers: Arc::new(ProviderPool::new(Some(fork_cache.clone()))),
+        session_slots: SessionSlots::default(),
+    });
+    let filter = warp::any()
+        .and(simulate_routes(config, shared_state))
+        .recover(handle_rejection);
+    let hash = stand_in_transactions()[1].hash;
+
+    for _ in 0..2 {
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/replay/{hash:?}"))
+            .json(&serde_json::json!({ "chainId": STAND_IN_CHAIN_ID }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+    }
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/fork-cache")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: ForkCacheStats = serde_json::from_slice(res.body()).unwrap();
+
+    // Everything is fetched at the parent of the replayed block
+    assert_eq!(body.blocks, 1);
+    assert!(body.misses > 0);
+    // The second replay is served from the cache
+    assert!(body.hits > 0);
+    assert!(body.size_bytes > 0);
+
+    // Written to disk, and picked up again on restart
+    fork_cache.flush().unwrap();
+    let reopened = ForkCache::open(dir, u64::MAX).unwrap();
+    assert_eq!(reopened.stats().blocks, 1);
+
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/fork-cache/{STAND_IN_CHAIN_ID}"))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: ForkCachePurgeResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.purged_blocks, 1);
+    assert_eq!(fork_cache.stats().blocks, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_fork_cache_disabled() {
+    let filter = filter(config());
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/fork-cache")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 404);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "FORK_CACHE_DISABLED");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_struct_logs() {
+    let filter = filter(stand_in_config());
+    let request = |trace_config: Value| {
+        serde_json::json!({
+            "chainId": STAND_IN_CHAIN_ID,
+            "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+            "to": counter_address(),
+            "gasLimit": 100000,
+            "traceConfig": trace_config,
+        })
+    };
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&request(serde_json::json!({ "enableMemory": true })))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    let struct_logs = body.struct_logs.unwrap();
+    let ops: Vec<&str> = struct_logs.iter().map(|log| log.op.as_str()).collect();
+    let slot = format!("{:x}", H256::zero());
+
+    assert_eq!(
+        ops,
+        [
+            "PUSH1", "SLOAD", "PUSH1", "ADD", "DUP1", "PUSH1", "SSTORE", "PUSH1", "MSTORE",
+            "PUSH1", "PUSH1", "RETURN"
+        ]
+    );
+    assert_eq!(struct_logs[0].pc, 0);
+    assert_eq!(struct_logs[0].depth, 1);
+    assert_eq!(struct_logs[0].gas_cost, 3);
+    assert_eq!(struct_logs[1].stack, Some(vec![U256::zero()]));
+    // A cold read, and a write from zero
+    assert_eq!(struct_logs[1].gas_cost, 2100);
+    assert_eq!(
+        struct_logs[1].storage.as_ref().unwrap()[&slot],
+        format!("{:x}", H256::zero())
+    );
+    assert_eq!(struct_logs[6].gas_cost, 20000);
+    assert_eq!(
+        struct_logs[6].storage.as_ref().unwrap()[&slot],
+        format!("{:x}", H256::from_low_u64_be(1))
+    );
+    assert_eq!(
+        struct_logs[11].memory,
+        Some(vec![format!("{:x}", H256::from_low_u64_be(1))])
+    );
+    assert_eq!(struct_logs[0].gas - struct_logs[11].gas, 22130);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&request(
+            serde_json::json!({ "limit": 5, "disableStack": true }),
+        ))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    let struct_logs = body.struct_logs.unwrap();
+
+    assert_eq!(struct_logs.len(), 5);
+    assert!(struct_logs
+        .iter()
+        .all(|log| log.stack.is_none() && log.memory.is_none()));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&request(Value::Null))
+        .reply(&filter)
+        .await;
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.struct_logs, None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_geth_tracers() {
+    let filter = filter(stand_in_config());
+    let slot = H256::zero();
+    let simulate = |trace_config: Value| {
+        let filter = filter.clone();
+        async move {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/simulate")
+                .json(&serde_json::json!({
+                    "chainId": STAND_IN_CHAIN_ID,
+                    "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+                    "to": counter_address(),
+                    "data": "0x12345678000000000000000000000000000000000000000000000000000000000000002a",
+                    "gasLimit": 100000,
+                    "traceConfig": trace_config,
+                }))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 200);
+            let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body.struct_logs, None);
+            body.tracer_result.unwrap()
+        }
+    };
+
+    let GethTrace::CallTracer(frame) =
+        simulate(serde_json::json!({ "tracer": "callTracer" })).await
+    else {
+        panic!("expected a call frame");
+    };
+
+    assert_eq!(frame.call_type, "CALL");
+    assert_eq!(frame.to, Some(counter_address()));
+    assert_eq!(frame.gas, U64::from(100000));
+    // The counter's first increment, plus the calldata: 5 non-zero bytes at 16 and 31 zero bytes
+    // at 4
+    assert_eq!(
+        frame.gas_used,
+        U64::from(STAND_IN_GAS_USED[0] + 5 * 16 + 31 * 4)
+    );
+    assert_eq!(
+        frame.output,
+        Some(Bytes::from(H256::from_low_u64_be(1).as_bytes().to_vec()))
+    );
+    assert_eq!(frame.error, None);
+    assert!(frame.calls.is_empty());
+
+    let GethTrace::Prestate(accounts) =
+        simulate(serde_json::json!({ "tracer": "prestateTracer" })).await
+    else {
+        panic!("expected the prestate");
+    };
+
+    let counter = &accounts[&counter_address()];
+    assert_eq!(counter.code, Some(COUNTER_CODE.parse().unwrap()));
+    assert_eq!(counter.storage[&slot], H256::zero());
+
+    let GethTrace::PrestateDiff(diff) = simulate(serde_json::json!({
+        "tracer": "prestateTracer",
+        "tracerConfig": { "diffMode": true }
+    }))
+    .await
+    else {
+        panic!("expected the prestate diff");
+    };
+
+    assert_eq!(diff.pre[&counter_address()].storage[&slot], H256::zero());
+    assert_eq!(
+        diff.post[&counter_address()].storage[&slot],
+        H256::from_low_u64_be(1)
+    );
+    // The code didn't change, so it's only in `pre`
+    assert_eq!(diff.post[&counter_address()].code, None);
+
+    let GethTrace::FourByteTracer(counts) =
+        simulate(serde_json::json!({ "tracer": "4byteTracer" })).await
+    else {
+        panic!("expected selector counts");
+    };
+
+    assert_eq!(counts.len(), 1);
+    assert_eq!(counts["0x12345678-32"], 1);
+}